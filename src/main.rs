@@ -3,6 +3,7 @@
 
 mod proxy;
 
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::error::Error;
 use std::fs;
 use std::path::{Path, PathBuf};
@@ -13,8 +14,23 @@ use i_slint_backend_winit::winit::window::ResizeDirection;
 use slint::{Model, VecModel, SharedString};
 use serde::{Deserialize, Serialize};
 use proxy::server::ProxyServer;
+use proxy::server::ProxySettings;
+use proxy::server::LogEvent;
 use proxy::server::Rule;
+use proxy::server::log_time;
+use proxy::headers::{CompiledHeaderRewrite, HeaderRewrite, Phase};
+use proxy::breakpoint::{Breakpoint, BreakpointEvent, CompiledBreakpoint, Decision, Resolution};
 use proxy::ca::CertificateAuthority;
+use proxy::matcher::{strip_brackets, Allowlist, DomainPattern, PathPattern};
+use proxy::auth::Credentials;
+use proxy::dns::{CompiledDnsOverride, DnsOverride};
+use proxy::nameserver;
+use proxy::capture::{Exchange, Timings};
+use proxy::remote::RemoteTarget;
+use proxy::throttle::Conditions;
+use proxy::chain::{Bypass, ProxyChoice, UpstreamProxy, DEFAULT_BYPASS};
+use proxy::websocket::{Delivery, Direction, WebSocketEvent, WebSocketMessage};
+use proxy::balance::{self, HealthEvent, DEFAULT_HEALTH_INTERVAL};
 
 mod ui {
     slint::include_modules!();
@@ -25,14 +41,186 @@ use ui::*;
 struct PersistedRule {
     id: String,
     domain: String,
+    #[serde(default)]
+    domain_regex: bool,
+    #[serde(default)]
+    path: String,
+    #[serde(default = "default_path_mode")]
+    path_mode: String,
+    #[serde(default)]
+    rewrite_path: bool,
+    #[serde(default)]
+    path_rewrite: String,
     target: String,
     protocol: String,
     enabled: bool,
+    #[serde(default)]
+    skip_verify: bool,
+    #[serde(default)]
+    cors_origins: String,
+    #[serde(default = "default_action")]
+    action: String,
+    #[serde(default)]
+    local_file: String,
+    #[serde(default)]
+    local_body: String,
+    #[serde(default = "default_local_status")]
+    local_status: u16,
+    #[serde(default)]
+    local_headers: String,
+    #[serde(default)]
+    local_content_type: String,
+    #[serde(default)]
+    remote_url: String,
+    #[serde(default)]
+    keep_host: bool,
+    #[serde(default = "default_throttle")]
+    throttle: String,
+    #[serde(default)]
+    upstream_proxy: String,
+    #[serde(default)]
+    ws_drop: String,
+    #[serde(default = "default_balance")]
+    balance: String,
+    #[serde(default)]
+    health_path: String,
+    #[serde(default = "default_health_interval")]
+    health_interval: u32,
+}
+
+fn default_path_mode() -> String {
+    "prefix".to_string()
+}
+
+fn default_action() -> String {
+    "forward".to_string()
+}
+
+fn default_local_status() -> u16 {
+    200
+}
+
+fn default_throttle() -> String {
+    "global".to_string()
+}
+
+fn default_balance() -> String {
+    "round-robin".to_string()
+}
+
+fn default_health_interval() -> u32 {
+    DEFAULT_HEALTH_INTERVAL
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PersistedHeaderRule {
+    id: String,
+    enabled: bool,
+    domain: String,
+    path: String,
+    phase: String,
+    action: String,
+    name: String,
+    value: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PersistedBreakpoint {
+    id: String,
+    enabled: bool,
+    domain: String,
+    path: String,
+    on_request: bool,
+    on_response: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PersistedDnsOverride {
+    id: String,
+    enabled: bool,
+    domain: String,
+    address: String,
+    #[serde(default)]
+    profile: String,
+}
+
+const MAX_LOGS: usize = 1000;
+// Per WebSocket connection, older messages are dropped
+const MAX_WS_MESSAGES: usize = 1000;
+
+// Full log events behind the logs model, newest first
+#[derive(Default)]
+struct CapturedLogs {
+    next_id: i32,
+    events: VecDeque<(i32, LogEvent)>,
+    // WebSocket messages by connection id, oldest first
+    ws_messages: HashMap<u64, VecDeque<WebSocketMessage>>,
+    ws_closed: HashSet<u64>,
+    // Connection whose messages are in the log detail pane
+    ws_shown: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 struct RuleStore {
     rules: Vec<PersistedRule>,
+    #[serde(default)]
+    header_rules: Vec<PersistedHeaderRule>,
+    #[serde(default)]
+    breakpoints: Vec<PersistedBreakpoint>,
+    #[serde(default)]
+    dns_overrides: Vec<PersistedDnsOverride>,
+    // Profiles whose overrides are switched off as a whole
+    #[serde(default)]
+    disabled_dns_profiles: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+struct AppSettings {
+    intercept_https: bool,
+    auto_issue_certs: bool,
+    cors_enabled: bool,
+    throttle_profile: String,
+    throttle_down_kbps: u32,
+    throttle_up_kbps: u32,
+    throttle_latency_ms: u32,
+    throttle_drop_percent: u32,
+    upstream_proxy: String,
+    proxy_bypass: String,
+    // Address the listeners bind to, see `parse_bind_address`
+    bind_address: String,
+    // Client addresses allowed to connect, see `Allowlist`
+    client_allowlist: String,
+    // Basic proxy authentication, off while the username is empty
+    proxy_username: String,
+    proxy_password: String,
+    // Where the name server forwards queries for other domains, empty = refuse them
+    dns_upstream: String,
+    // Hosts the PAC script always sends direct, see `Bypass`
+    pac_bypass: String,
+}
+
+impl Default for AppSettings {
+    fn default() -> Self {
+        Self {
+            intercept_https: false,
+            auto_issue_certs: true,
+            cors_enabled: false,
+            throttle_profile: "off".to_string(),
+            throttle_down_kbps: 1000,
+            throttle_up_kbps: 500,
+            throttle_latency_ms: 300,
+            throttle_drop_percent: 0,
+            upstream_proxy: String::new(),
+            proxy_bypass: DEFAULT_BYPASS.to_string(),
+            bind_address: "127.0.0.1".to_string(),
+            client_allowlist: String::new(),
+            proxy_username: String::new(),
+            proxy_password: String::new(),
+            dns_upstream: String::new(),
+            pac_bypass: String::new(),
+        }
+    }
 }
 
 /// 设置 Windows DPI 感知以改善字体渲染
@@ -68,46 +256,277 @@ async fn main() -> Result<(), Box<dyn Error>> {
     
     // Create channel for logs
     let (log_sender, mut log_receiver) = tokio::sync::mpsc::channel(100);
+    // Paused requests/responses waiting in the breakpoints tab
+    let (breakpoint_sender, mut breakpoint_receiver) = tokio::sync::mpsc::channel(100);
+    // Frames passing through proxied WebSockets
+    let (websocket_sender, mut websocket_receiver) = tokio::sync::mpsc::channel(100);
+    // Health check results of load-balanced rule targets
+    let (health_sender, mut health_receiver) = tokio::sync::mpsc::channel(100);
     
-    let proxy_server = Arc::new(ProxyServer::new(log_sender));
+    let ca = Arc::new(CertificateAuthority::new());
+    let proxy_server = Arc::new(ProxyServer::new(log_sender, breakpoint_sender, websocket_sender, health_sender, ca.clone()));
     let proxy_running = Arc::new(Mutex::new(false));
 
+    // 代理设置
+    let settings = load_settings();
+    ui.set_intercept_https(settings.intercept_https);
+    ui.set_auto_issue_certs(settings.auto_issue_certs);
+    ui.set_cors_enabled(settings.cors_enabled);
+    ui.set_throttle_profile(SharedString::from(settings.throttle_profile.clone()));
+    ui.set_throttle_down_kbps(SharedString::from(settings.throttle_down_kbps.to_string()));
+    ui.set_throttle_up_kbps(SharedString::from(settings.throttle_up_kbps.to_string()));
+    ui.set_throttle_latency_ms(SharedString::from(settings.throttle_latency_ms.to_string()));
+    ui.set_throttle_drop_percent(SharedString::from(settings.throttle_drop_percent.to_string()));
+    ui.set_upstream_proxy(SharedString::from(settings.upstream_proxy.clone()));
+    ui.set_proxy_bypass(SharedString::from(settings.proxy_bypass.clone()));
+    ui.set_bind_address(SharedString::from(settings.bind_address.clone()));
+    ui.set_client_allowlist(SharedString::from(settings.client_allowlist.clone()));
+    ui.set_proxy_username(SharedString::from(settings.proxy_username.clone()));
+    ui.set_proxy_password(SharedString::from(settings.proxy_password.clone()));
+    ui.set_dns_upstream(SharedString::from(settings.dns_upstream.clone()));
+    ui.set_dns_error(SharedString::from(dns_error(&settings)));
+    ui.set_pac_bypass(SharedString::from(settings.pac_bypass.clone()));
+    ui.set_pac_error(SharedString::from(pac_error(&settings)));
+    ui.set_listen_error(SharedString::from(listen_error(&settings)));
+    if let Some(addr) = lan_address() {
+        ui.set_lan_address(SharedString::from(addr.to_string()));
+    }
+    let (backend_settings, error) = proxy_settings(&settings);
+    ui.set_upstream_proxy_error(SharedString::from(error));
+    proxy_server.update_settings(backend_settings);
+
+    ui.on_settings_changed({
+        let proxy_server = proxy_server.clone();
+        let ui_handle = ui.as_weak();
+        move || {
+            let ui = ui_handle.unwrap();
+            let settings = AppSettings {
+                intercept_https: ui.get_intercept_https(),
+                auto_issue_certs: ui.get_auto_issue_certs(),
+                cors_enabled: ui.get_cors_enabled(),
+                throttle_profile: ui.get_throttle_profile().to_string(),
+                // Unparsable numbers count as 0, i.e. unlimited / no latency / no drops
+                throttle_down_kbps: ui.get_throttle_down_kbps().trim().parse().unwrap_or(0),
+                throttle_up_kbps: ui.get_throttle_up_kbps().trim().parse().unwrap_or(0),
+                throttle_latency_ms: ui.get_throttle_latency_ms().trim().parse().unwrap_or(0),
+                throttle_drop_percent: ui.get_throttle_drop_percent().trim().parse::<u32>().unwrap_or(0).min(100),
+                upstream_proxy: ui.get_upstream_proxy().trim().to_string(),
+                proxy_bypass: ui.get_proxy_bypass().to_string(),
+                bind_address: ui.get_bind_address().trim().to_string(),
+                client_allowlist: ui.get_client_allowlist().to_string(),
+                proxy_username: ui.get_proxy_username().trim().to_string(),
+                proxy_password: ui.get_proxy_password().to_string(),
+                dns_upstream: ui.get_dns_upstream().trim().to_string(),
+                pac_bypass: ui.get_pac_bypass().to_string(),
+            };
+            ui.set_listen_error(SharedString::from(listen_error(&settings)));
+            ui.set_dns_error(SharedString::from(dns_error(&settings)));
+            ui.set_pac_error(SharedString::from(pac_error(&settings)));
+            let (backend_settings, error) = proxy_settings(&settings);
+            ui.set_upstream_proxy_error(SharedString::from(error));
+            proxy_server.update_settings(backend_settings);
+            persist_settings(&settings);
+        }
+    });
+
     // Logs Model
     let logs_model = Rc::new(VecModel::default());
     ui.set_logs(logs_model.clone().into());
 
+    // Full events behind the logs model, for HAR export
+    let captured_logs = Arc::new(Mutex::new(CapturedLogs::default()));
+
     // Handle incoming logs
     let ui_handle = ui.as_weak();
+    let captured = captured_logs.clone();
     tokio::spawn(async move {
         while let Some(event) = log_receiver.recv().await {
             let ui_handle = ui_handle.clone();
+            let captured = captured.clone();
             let _ = slint::invoke_from_event_loop(move || {
                 if let Some(ui) = ui_handle.upgrade() {
-                    let logs_model = ui.get_logs();
-                    if let Some(vec_model) = logs_model.as_any().downcast_ref::<VecModel<LogEntry>>() {
-                        vec_model.insert(0, LogEntry {
-                            time: SharedString::from(&event.time),
-                            method: SharedString::from(&event.method),
-                            protocol: SharedString::from(&event.protocol),
-                            url: SharedString::from(&event.url),
-                            status: SharedString::from(&event.status),
-                            status_code: event.status_code,
-                        });
-                        // Keep only last 1000 logs
-                        if vec_model.row_count() > 1000 {
-                            vec_model.remove(1000);
-                        }
-                    }
+                    push_log(&ui, &captured, event);
+                }
+            });
+        }
+    });
+
+    // Messages of the WebSocket selected in the logs
+    let ws_messages_model = Rc::new(VecModel::<WsMessage>::default());
+    ui.set_ws_messages(ws_messages_model.clone().into());
+
+    let ui_handle = ui.as_weak();
+    let captured = captured_logs.clone();
+    tokio::spawn(async move {
+        while let Some(event) = websocket_receiver.recv().await {
+            let ui_handle = ui_handle.clone();
+            let captured = captured.clone();
+            let _ = slint::invoke_from_event_loop(move || {
+                if let Some(ui) = ui_handle.upgrade() {
+                    push_websocket_event(&ui, &captured, event);
                 }
             });
         }
     });
 
+    let ws_messages_model_clone = ws_messages_model.clone();
+    let ui_handle = ui.as_weak();
+    let captured = captured_logs.clone();
+    ui.on_show_ws_messages(move |connection| {
+        let Ok(mut captured) = captured.lock() else {
+            return;
+        };
+        let connection = connection as u64;
+        captured.ws_shown = connection;
+        let messages: Vec<_> = captured
+            .ws_messages
+            .get(&connection)
+            .map(|messages| messages.iter().map(ws_message).collect())
+            .unwrap_or_default();
+        ws_messages_model_clone.set_vec(messages);
+        if let Some(ui) = ui_handle.upgrade() {
+            ui.set_ws_open(!captured.ws_closed.contains(&connection));
+        }
+    });
+
+    let proxy_server_clone = proxy_server.clone();
+    ui.on_send_ws_message(move |connection, text, to_server| {
+        let direction = if to_server { Direction::ToServer } else { Direction::ToClient };
+        if !proxy_server_clone.send_websocket_message(connection as u64, direction, text.to_string()) {
+            println!("WebSocket {} is already closed", connection);
+        }
+    });
+
     let logs_model_clone = logs_model.clone();
+    let ws_messages_model_clone = ws_messages_model.clone();
+    let ui_handle = ui.as_weak();
+    let captured = captured_logs.clone();
     ui.on_clear_logs(move || {
         while logs_model_clone.row_count() > 0 {
             logs_model_clone.remove(0);
         }
+        ws_messages_model_clone.set_vec(Vec::new());
+        if let Ok(mut captured) = captured.lock() {
+            captured.events.clear();
+            captured.ws_messages.clear();
+            captured.ws_closed.clear();
+            captured.ws_shown = 0;
+        }
+        if let Some(ui) = ui_handle.upgrade() {
+            ui.set_selected_log_id(-1);
+            ui.set_marked_log_count(0);
+        }
+    });
+
+    let logs_model_clone = logs_model.clone();
+    let ui_handle = ui.as_weak();
+    ui.on_toggle_log_mark(move |id| {
+        let mut marked = 0;
+        for row in 0..logs_model_clone.row_count() {
+            let Some(mut entry) = logs_model_clone.row_data(row) else {
+                continue;
+            };
+            if entry.id == id {
+                entry.marked = !entry.marked;
+                logs_model_clone.set_row_data(row, entry.clone());
+            }
+            if entry.marked {
+                marked += 1;
+            }
+        }
+        if let Some(ui) = ui_handle.upgrade() {
+            ui.set_marked_log_count(marked);
+        }
+    });
+
+    // Export the marked entries, or everything when nothing is marked
+    let logs_model_clone = logs_model.clone();
+    let captured = captured_logs.clone();
+    ui.on_export_har(move || {
+        let marked: Vec<i32> = logs_model_clone.iter().filter(|entry| entry.marked).map(|entry| entry.id).collect();
+        let events: Vec<LogEvent> = match captured.lock() {
+            Ok(captured) => captured
+                .events
+                .iter()
+                .rev()
+                .filter(|(id, _)| marked.is_empty() || marked.contains(id))
+                .map(|(_, event)| event.clone())
+                .collect(),
+            Err(_) => return,
+        };
+        let har = match proxy::har::export(&events) {
+            Ok(har) => har,
+            Err(e) => {
+                eprintln!("Failed to export HAR: {}", e);
+                return;
+            }
+        };
+
+        let task = rfd::AsyncFileDialog::new()
+            .set_title("Export HAR")
+            .set_file_name("ovo.har")
+            .add_filter("HAR", &["har"])
+            .save_file();
+
+        tokio::spawn(async move {
+            if let Some(file) = task.await {
+                match std::fs::write(file.path(), har) {
+                    Ok(_) => println!("HAR exported to: {:?}", file.path()),
+                    Err(e) => eprintln!("Failed to export HAR: {}", e),
+                }
+            }
+        });
+    });
+
+    let ui_handle = ui.as_weak();
+    let captured = captured_logs.clone();
+    ui.on_import_har(move || {
+        let task = rfd::AsyncFileDialog::new()
+            .set_title("Import HAR")
+            .add_filter("HAR", &["har", "json"])
+            .pick_file();
+
+        let ui_handle = ui_handle.clone();
+        let captured = captured.clone();
+        tokio::spawn(async move {
+            let Some(file) = task.await else {
+                return;
+            };
+            let path = file.path().to_path_buf();
+            let result = fs::read_to_string(&path)
+                .map_err(|e| format!("读取 {} 失败: {}", path.display(), e))
+                .and_then(|text| proxy::har::import(&text));
+
+            let _ = slint::invoke_from_event_loop(move || {
+                let Some(ui) = ui_handle.upgrade() else {
+                    return;
+                };
+                // Import problems show up in the log itself
+                let (url, status, status_code) = match result {
+                    Ok(events) => {
+                        let count = events.len();
+                        for event in events {
+                            push_log(&ui, &captured, event);
+                        }
+                        (format!("Imported {} entries from {}", count, path.display()), "OK".to_string(), 200)
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to import HAR: {}", e);
+                        (e, "Import Failed".to_string(), 500)
+                    }
+                };
+                push_log(&ui, &captured, LogEvent {
+                    time: log_time(),
+                    method: "SYSTEM".to_string(),
+                    protocol: "SYSTEM".to_string(),
+                    url,
+                    status,
+                    status_code,
+                    upstream_url: String::new(),
+                    exchange: None,
+                });
+            });
+        });
     });
 
     // 检测并设置平台类型
@@ -151,7 +570,59 @@ async fn main() -> Result<(), Box<dyn Error>> {
         }
     });
 
-    ui.on_validate_target_port(|target| is_valid_port(&target));
+    // Every entry of a target list must be valid, see `balance::parse_targets`
+    ui.on_validate_target(|target| {
+        balance::parse_targets(&target)
+            .is_ok_and(|targets| !targets.is_empty() && targets.iter().all(|(target, _)| is_valid_target(target)))
+    });
+    ui.on_validate_domain(|domain, regex| {
+        match DomainPattern::parse(&domain, regex) {
+            Ok(_) => SharedString::default(),
+            Err(e) => SharedString::from(e),
+        }
+    });
+    ui.on_validate_path(|path, mode| {
+        match PathPattern::parse(&path, &mode) {
+            Ok(_) => SharedString::default(),
+            Err(e) => SharedString::from(e),
+        }
+    });
+
+    ui.on_validate_remote(|url| match RemoteTarget::parse(&url) {
+        Ok(_) => SharedString::new(),
+        Err(e) => SharedString::from(e),
+    });
+
+    ui.on_validate_upstream_proxy(|value| match ProxyChoice::parse(&value) {
+        Ok(_) => SharedString::new(),
+        Err(e) => SharedString::from(e),
+    });
+
+    ui.on_validate_ws_drop(|pattern| match proxy::websocket::drop_pattern(&pattern) {
+        Ok(_) => SharedString::new(),
+        Err(e) => SharedString::from(e),
+    });
+
+    ui.on_validate_health(|path, interval| {
+        let path = path.trim();
+        if !path.is_empty() && !path.starts_with('/') {
+            SharedString::from("健康检查路径必须以 / 开头")
+        } else if !interval.trim().parse::<u32>().is_ok_and(|seconds| (1..=3600).contains(&seconds)) {
+            SharedString::from("检查间隔必须是 1-3600 秒")
+        } else {
+            SharedString::new()
+        }
+    });
+
+    ui.on_validate_local(|status, headers| {
+        if !status.parse::<u16>().is_ok_and(|status| (100..=599).contains(&status)) {
+            return SharedString::from("状态码必须是 100-599 的整数");
+        }
+        match proxy::local::parse_headers(&headers) {
+            Ok(_) => SharedString::new(),
+            Err(e) => SharedString::from(e),
+        }
+    });
 
     // 最大化/恢复窗口
     ui.on_maximize_window({
@@ -235,16 +706,27 @@ async fn main() -> Result<(), Box<dyn Error>> {
     load_rules_into_model(&rules_model);
     update_backend_rules(&proxy_server, &rules_model);
 
+    let ui_handle = ui.as_weak();
+    tokio::spawn(async move {
+        while let Some(event) = health_receiver.recv().await {
+            let ui_handle = ui_handle.clone();
+            let _ = slint::invoke_from_event_loop(move || {
+                if let Some(ui) = ui_handle.upgrade() {
+                    show_target_health(&ui, event);
+                }
+            });
+        }
+    });
+
     let proxy_server_clone = proxy_server.clone();
     let rules_model_clone = rules_model.clone();
-    ui.on_add_rule(move |domain, target, protocol| {
+    ui.on_add_rule(move |rule| {
         let id = uuid::Uuid::new_v4().to_string();
         let rule = ProxyRule {
             id: SharedString::from(&id),
-            domain,
-            target,
-            protocol,
             enabled: true,
+            health: target_health(&rule, None),
+            ..rule
         };
         rules_model_clone.push(rule);
 
@@ -254,28 +736,28 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     let proxy_server_clone = proxy_server.clone();
     let rules_model_clone = rules_model.clone();
-    ui.on_update_rule(move |id, domain, target, protocol| {
+    ui.on_update_rule(move |updated| {
         let mut index_to_update = None;
         let mut enabled = true;
+        let mut health = None;
 
         for (i, rule) in rules_model_clone.iter().enumerate() {
-            if rule.id == id {
+            if rule.id == updated.id {
                 index_to_update = Some(i);
                 enabled = rule.enabled;
+                health = Some(target_health(&updated, Some(&rule)));
                 break;
             }
         }
 
-        if let Some(i) = index_to_update {
+        if let (Some(i), Some(health)) = (index_to_update, health) {
             rules_model_clone.remove(i);
             rules_model_clone.insert(
                 i,
                 ProxyRule {
-                    id,
-                    domain,
-                    target,
-                    protocol,
                     enabled,
+                    health,
+                    ..updated
                 },
             );
 
@@ -301,8 +783,171 @@ async fn main() -> Result<(), Box<dyn Error>> {
         }
     });
 
+    // Header 改写
+    let header_rules_model = Rc::new(VecModel::default());
+    ui.set_header_rules(header_rules_model.clone().into());
+
+    load_header_rules_into_model(&header_rules_model);
+    update_backend_header_rules(&proxy_server, &header_rules_model);
+
+    ui.on_validate_header_rule(|rule| match CompiledHeaderRewrite::compile(&header_rewrite(&rule)) {
+        Ok(_) => SharedString::new(),
+        Err(e) => SharedString::from(e),
+    });
+
+    let proxy_server_clone = proxy_server.clone();
+    let header_rules_model_clone = header_rules_model.clone();
+    ui.on_add_header_rule(move |rule| {
+        let id = uuid::Uuid::new_v4().to_string();
+        header_rules_model_clone.push(HeaderRule {
+            id: SharedString::from(&id),
+            enabled: true,
+            ..rule
+        });
+
+        update_backend_header_rules(&proxy_server_clone, &header_rules_model_clone);
+        persist_header_rules(&header_rules_model_clone);
+    });
+
+    let proxy_server_clone = proxy_server.clone();
+    let header_rules_model_clone = header_rules_model.clone();
+    ui.on_toggle_header_rule(move |id, enabled| {
+        if let Some(i) = header_rules_model_clone.iter().position(|rule| rule.id == id) {
+            let rule = header_rules_model_clone.row_data(i).unwrap();
+            header_rules_model_clone.set_row_data(i, HeaderRule { enabled, ..rule });
+
+            update_backend_header_rules(&proxy_server_clone, &header_rules_model_clone);
+            persist_header_rules(&header_rules_model_clone);
+        }
+    });
+
+    let proxy_server_clone = proxy_server.clone();
+    let header_rules_model_clone = header_rules_model.clone();
+    ui.on_remove_header_rule(move |id| {
+        if let Some(i) = header_rules_model_clone.iter().position(|rule| rule.id == id) {
+            header_rules_model_clone.remove(i);
+            update_backend_header_rules(&proxy_server_clone, &header_rules_model_clone);
+            persist_header_rules(&header_rules_model_clone);
+        }
+    });
+
+    // 断点
+    let breakpoints_model = Rc::new(VecModel::default());
+    ui.set_breakpoints(breakpoints_model.clone().into());
+
+    load_breakpoints_into_model(&breakpoints_model);
+    update_backend_breakpoints(&proxy_server, &breakpoints_model);
+
+    ui.on_validate_breakpoint(|breakpoint| match CompiledBreakpoint::compile(&backend_breakpoint(&breakpoint)) {
+        Ok(_) => SharedString::new(),
+        Err(e) => SharedString::from(e),
+    });
+
+    let proxy_server_clone = proxy_server.clone();
+    let breakpoints_model_clone = breakpoints_model.clone();
+    ui.on_add_breakpoint(move |breakpoint| {
+        let id = uuid::Uuid::new_v4().to_string();
+        breakpoints_model_clone.push(BreakpointRule {
+            id: SharedString::from(&id),
+            enabled: true,
+            ..breakpoint
+        });
+
+        update_backend_breakpoints(&proxy_server_clone, &breakpoints_model_clone);
+        persist_breakpoints(&breakpoints_model_clone);
+    });
+
+    let proxy_server_clone = proxy_server.clone();
+    let breakpoints_model_clone = breakpoints_model.clone();
+    ui.on_toggle_breakpoint(move |id, enabled| {
+        if let Some(i) = breakpoints_model_clone.iter().position(|breakpoint| breakpoint.id == id) {
+            let breakpoint = breakpoints_model_clone.row_data(i).unwrap();
+            breakpoints_model_clone.set_row_data(i, BreakpointRule { enabled, ..breakpoint });
+
+            update_backend_breakpoints(&proxy_server_clone, &breakpoints_model_clone);
+            persist_breakpoints(&breakpoints_model_clone);
+        }
+    });
+
+    let proxy_server_clone = proxy_server.clone();
+    let breakpoints_model_clone = breakpoints_model.clone();
+    ui.on_remove_breakpoint(move |id| {
+        if let Some(i) = breakpoints_model_clone.iter().position(|breakpoint| breakpoint.id == id) {
+            breakpoints_model_clone.remove(i);
+            update_backend_breakpoints(&proxy_server_clone, &breakpoints_model_clone);
+            persist_breakpoints(&breakpoints_model_clone);
+        }
+    });
+
+    let paused_model = Rc::new(VecModel::<PausedItem>::default());
+    ui.set_paused(paused_model.clone().into());
+
+    let ui_handle = ui.as_weak();
+    tokio::spawn(async move {
+        while let Some(event) = breakpoint_receiver.recv().await {
+            let ui_handle = ui_handle.clone();
+            let _ = slint::invoke_from_event_loop(move || {
+                let Some(ui) = ui_handle.upgrade() else {
+                    return;
+                };
+                let paused = ui.get_paused();
+                let Some(vec_model) = paused.as_any().downcast_ref::<VecModel<PausedItem>>() else {
+                    return;
+                };
+
+                match event {
+                    BreakpointEvent::Paused(message) => vec_model.push(PausedItem {
+                        id: message.id as i32,
+                        phase: SharedString::from(match message.phase {
+                            Phase::Request => "request",
+                            Phase::Response => "response",
+                        }),
+                        method: SharedString::from(message.method),
+                        url: SharedString::from(message.url),
+                        status: SharedString::from(message.status.to_string()),
+                        headers: SharedString::from(message.headers),
+                        body: SharedString::from(message.body),
+                        binary: message.binary,
+                    }),
+                    BreakpointEvent::Released(id) => {
+                        if let Some(i) = vec_model.iter().position(|item| item.id == id as i32) {
+                            vec_model.remove(i);
+                        }
+                        if ui.get_paused_selected_id() == id as i32 {
+                            ui.set_paused_selected_id(-1);
+                        }
+                    }
+                }
+            });
+        }
+    });
+
+    let proxy_server_clone = proxy_server.clone();
+    let paused_model_clone = paused_model.clone();
+    ui.on_resolve_breakpoint(move |item, action| {
+        let resolution = match action.as_str() {
+            "abort" => Resolution::Abort,
+            "respond" => Resolution::Respond,
+            _ => Resolution::Continue,
+        };
+        let decision = Decision {
+            resolution,
+            method: item.method.to_string(),
+            url: item.url.to_string(),
+            status: item.status.parse().unwrap_or(200),
+            headers: item.headers.to_string(),
+            body: item.body.to_string(),
+        };
+        if !proxy_server_clone.resolve_breakpoint(item.id as u64, decision) {
+            println!("Breakpoint {} was already released", item.id);
+        }
+
+        if let Some(i) = paused_model_clone.iter().position(|paused| paused.id == item.id) {
+            paused_model_clone.remove(i);
+        }
+    });
+
     // 证书管理
-    let ca = Arc::new(CertificateAuthority::new());
     let certs_model = Rc::new(VecModel::default());
     ui.set_certificates(certs_model.clone().into());
 
@@ -418,6 +1063,85 @@ async fn main() -> Result<(), Box<dyn Error>> {
         }
     });
 
+    // DNS 覆盖
+    let dns_rules_model = Rc::new(VecModel::default());
+    ui.set_dns_rules(dns_rules_model.clone().into());
+    let dns_profiles_model = Rc::new(VecModel::default());
+    ui.set_dns_profiles(dns_profiles_model.clone().into());
+
+    load_dns_rules_into_model(&dns_rules_model);
+    dns_profiles_model.set_vec(dns_profiles(&dns_rules_model));
+    update_backend_dns_overrides(&proxy_server, &dns_rules_model);
+
+    ui.on_validate_dns_rule(|rule| match CompiledDnsOverride::compile(&dns_override(&rule)) {
+        Ok(_) => SharedString::new(),
+        Err(e) => SharedString::from(e),
+    });
+
+    let proxy_server_clone = proxy_server.clone();
+    let dns_rules_model_clone = dns_rules_model.clone();
+    let dns_profiles_model_clone = dns_profiles_model.clone();
+    ui.on_add_dns_rule(move |rule| {
+        let profile = rule.profile.trim().to_string();
+        // A new entry joins its profile as it is switched
+        let profile_enabled = dns_rules_model_clone
+            .iter()
+            .find(|existing| existing.profile == profile)
+            .is_none_or(|existing| existing.profile_enabled);
+        dns_rules_model_clone.push(DnsRule {
+            id: SharedString::from(uuid::Uuid::new_v4().to_string()),
+            enabled: true,
+            domain: SharedString::from(rule.domain.trim()),
+            address: SharedString::from(rule.address.trim()),
+            profile: SharedString::from(profile),
+            profile_enabled,
+        });
+
+        dns_profiles_model_clone.set_vec(dns_profiles(&dns_rules_model_clone));
+        update_backend_dns_overrides(&proxy_server_clone, &dns_rules_model_clone);
+        persist_dns_rules(&dns_rules_model_clone);
+    });
+
+    let proxy_server_clone = proxy_server.clone();
+    let dns_rules_model_clone = dns_rules_model.clone();
+    ui.on_toggle_dns_rule(move |id, enabled| {
+        if let Some(i) = dns_rules_model_clone.iter().position(|rule| rule.id == id) {
+            let rule = dns_rules_model_clone.row_data(i).unwrap();
+            dns_rules_model_clone.set_row_data(i, DnsRule { enabled, ..rule });
+
+            update_backend_dns_overrides(&proxy_server_clone, &dns_rules_model_clone);
+            persist_dns_rules(&dns_rules_model_clone);
+        }
+    });
+
+    let proxy_server_clone = proxy_server.clone();
+    let dns_rules_model_clone = dns_rules_model.clone();
+    let dns_profiles_model_clone = dns_profiles_model.clone();
+    ui.on_remove_dns_rule(move |id| {
+        if let Some(i) = dns_rules_model_clone.iter().position(|rule| rule.id == id) {
+            dns_rules_model_clone.remove(i);
+            dns_profiles_model_clone.set_vec(dns_profiles(&dns_rules_model_clone));
+            update_backend_dns_overrides(&proxy_server_clone, &dns_rules_model_clone);
+            persist_dns_rules(&dns_rules_model_clone);
+        }
+    });
+
+    let proxy_server_clone = proxy_server.clone();
+    let dns_rules_model_clone = dns_rules_model.clone();
+    let dns_profiles_model_clone = dns_profiles_model.clone();
+    ui.on_toggle_dns_profile(move |name, enabled| {
+        for i in 0..dns_rules_model_clone.row_count() {
+            let rule = dns_rules_model_clone.row_data(i).unwrap();
+            if rule.profile == name {
+                dns_rules_model_clone.set_row_data(i, DnsRule { profile_enabled: enabled, ..rule });
+            }
+        }
+
+        dns_profiles_model_clone.set_vec(dns_profiles(&dns_rules_model_clone));
+        update_backend_dns_overrides(&proxy_server_clone, &dns_rules_model_clone);
+        persist_dns_rules(&dns_rules_model_clone);
+    });
+
     // API 测试器
     let api_history_model = Rc::new(VecModel::default());
     ui.set_api_history(api_history_model.clone().into());
@@ -488,6 +1212,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
     });
 
     // 选择历史记录
+    #[allow(clippy::collapsible_if)]
     ui.on_api_select_history({
         let ui_handle = ui.as_weak();
         let api_history = api_history_model.clone();
@@ -564,11 +1289,22 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 // Read configuration from UI
                 let http_port = ui.get_http_port().parse::<u16>().unwrap_or(80);
                 let https_port = ui.get_https_port().parse::<u16>().unwrap_or(443);
-                let ports = vec![http_port, https_port];
+                // Empty or invalid leaves the SOCKS listener off
+                let socks_port = ui.get_socks_port().trim().parse::<u16>().ok().filter(|port| *port != 0);
+                // Likewise for the name server
+                let dns_port = ui.get_dns_port().trim().parse::<u16>().ok().filter(|port| *port != 0);
+                let bind = match parse_bind_address(&ui.get_bind_address()) {
+                    Ok(bind) => bind,
+                    Err(e) => {
+                        ui.invoke_show_error(SharedString::from(e));
+                        ui.set_proxy_running(false);
+                        return;
+                    }
+                };
                 
                 *running = true;
                 tokio::spawn(async move {
-                    if let Err(e) = server.start(ports).await {
+                    if let Err(e) = server.start(bind, http_port, https_port, socks_port, dns_port).await {
                         eprintln!("Proxy server error: {}", e);
                         
                         // Check if it's a permission error
@@ -588,7 +1324,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
                         });
                     }
                 });
-                println!("Proxy started on ports {}, {}", http_port, https_port);
+                println!("Proxy started on {} ports {}, {}", bind, http_port, https_port);
             } else if !enable && *running {
                 proxy_server.stop();
                 *running = false;
@@ -613,14 +1349,272 @@ fn update_backend_rules(server: &Arc<ProxyServer>, model: &Rc<VecModel<ProxyRule
         rules.push(Rule {
             id: rule.id.to_string(),
             domain: rule.domain.to_string(),
+            domain_regex: rule.domain_regex,
+            path: rule.path.to_string(),
+            path_mode: rule.path_mode.to_string(),
+            rewrite_path: rule.rewrite_path,
+            path_rewrite: rule.path_rewrite.to_string(),
             target: rule.target.to_string(),
             protocol: rule.protocol.to_string(),
             enabled: rule.enabled,
+            skip_verify: rule.skip_verify,
+            cors_origins: rule.cors_origins.to_string(),
+            action: rule.action.to_string(),
+            local_file: rule.local_file.to_string(),
+            local_body: rule.local_body.to_string(),
+            local_status: rule.local_status.parse().unwrap_or(200),
+            local_headers: rule.local_headers.to_string(),
+            local_content_type: rule.local_content_type.to_string(),
+            remote_url: rule.remote_url.to_string(),
+            keep_host: rule.keep_host,
+            throttle: rule.throttle.to_string(),
+            upstream_proxy: rule.upstream_proxy.trim().to_string(),
+            ws_drop: rule.ws_drop.trim().to_string(),
+            balance: rule.balance.to_string(),
+            health_path: rule.health_path.trim().to_string(),
+            health_interval: rule.health_interval.trim().parse().unwrap_or(DEFAULT_HEALTH_INTERVAL),
         });
     }
     server.update_rules(rules);
 }
 
+// Add `event` to the top of the logs, keeping the last `MAX_LOGS`
+fn push_log(ui: &AppWindow, captured: &Mutex<CapturedLogs>, event: LogEvent) {
+    let Ok(mut captured) = captured.lock() else {
+        return;
+    };
+    captured.next_id += 1;
+    let id = captured.next_id;
+
+    let logs_model = ui.get_logs();
+    if let Some(vec_model) = logs_model.as_any().downcast_ref::<VecModel<LogEntry>>() {
+        vec_model.insert(0, log_entry(id, &event));
+        if vec_model.row_count() > MAX_LOGS {
+            vec_model.remove(MAX_LOGS);
+        }
+    }
+    captured.events.push_front((id, event));
+    while captured.events.len() > MAX_LOGS {
+        let websocket = captured.events.pop_back().and_then(|(_, event)| event.exchange?.websocket);
+        if let Some(connection) = websocket {
+            captured.ws_messages.remove(&connection);
+            captured.ws_closed.remove(&connection);
+        }
+    }
+}
+
+// Record a WebSocket message, showing it right away when its connection is selected
+fn push_websocket_event(ui: &AppWindow, captured: &Mutex<CapturedLogs>, event: WebSocketEvent) {
+    let Ok(mut captured) = captured.lock() else {
+        return;
+    };
+    let shown = captured.ws_shown;
+
+    match event {
+        WebSocketEvent::Message(message) => {
+            if message.connection == shown {
+                let ws_messages = ui.get_ws_messages();
+                if let Some(vec_model) = ws_messages.as_any().downcast_ref::<VecModel<WsMessage>>() {
+                    vec_model.push(ws_message(&message));
+                    if vec_model.row_count() > MAX_WS_MESSAGES {
+                        vec_model.remove(0);
+                    }
+                }
+            }
+            let messages = captured.ws_messages.entry(message.connection).or_default();
+            messages.push_back(message);
+            if messages.len() > MAX_WS_MESSAGES {
+                messages.pop_front();
+            }
+        }
+        WebSocketEvent::Closed(connection) => {
+            captured.ws_closed.insert(connection);
+            if connection == shown {
+                ui.set_ws_open(false);
+            }
+        }
+    }
+}
+
+fn ws_message(message: &WebSocketMessage) -> WsMessage {
+    WsMessage {
+        time: SharedString::from(&message.time),
+        to_server: message.direction == Direction::ToServer,
+        kind: SharedString::from(message.kind()),
+        size: SharedString::from(format_size(message.size)),
+        delivery: SharedString::from(match message.delivery {
+            Delivery::Forwarded => "",
+            Delivery::Dropped => "已丢弃",
+            Delivery::Injected => "手动发送",
+        }),
+        preview: SharedString::from(&message.preview),
+    }
+}
+
+fn log_entry(id: i32, event: &LogEvent) -> LogEntry {
+    // Borrowed rather than cloned, as the entry only keeps previews of the bodies
+    let none = Exchange::default();
+    let exchange = event.exchange.as_ref().unwrap_or(&none);
+    LogEntry {
+        id,
+        time: SharedString::from(&event.time),
+        method: SharedString::from(&event.method),
+        protocol: SharedString::from(&event.protocol),
+        url: SharedString::from(&event.url),
+        status: SharedString::from(&event.status),
+        status_code: event.status_code,
+        upstream_url: SharedString::from(&event.upstream_url),
+        has_detail: event.exchange.is_some(),
+        client_addr: SharedString::from(&exchange.client_addr),
+        rule_id: SharedString::from(&exchange.rule_id),
+        dns_override: SharedString::from(&exchange.dns_override),
+        request_headers: SharedString::from(&exchange.request_headers),
+        request_body: SharedString::from(body_preview(&exchange.request_body, exchange.request_size)),
+        request_size: SharedString::from(format_size(exchange.request_size)),
+        response_headers: SharedString::from(&exchange.response_headers),
+        response_body: SharedString::from(body_preview(&exchange.response_body, exchange.response_size)),
+        response_size: SharedString::from(format_size(exchange.response_size)),
+        duration: SharedString::from(format!("{} ms", exchange.timings.total.as_millis())),
+        phases: slint::ModelRc::new(VecModel::from(timing_phases(&exchange.timings))),
+        connection: SharedString::from(if exchange.timings.reused {
+            "复用已有连接"
+        } else if exchange.timings.connect.is_some() {
+            "新建连接"
+        } else {
+            ""
+        }),
+        complete: exchange.complete,
+        websocket: exchange.websocket.map_or(0, |connection| connection as i32),
+        marked: false,
+    }
+}
+
+// Bars of the timing waterfall, laid out one after another over the exchange
+fn timing_phases(timings: &Timings) -> Vec<TimingPhase> {
+    if timings.wait.is_zero() && timings.receive.is_zero() {
+        // Tunnels and imported entries without a breakdown
+        return Vec::new();
+    }
+    let phases = [
+        ("blocked", "排队", Some(timings.blocked).filter(|blocked| !blocked.is_zero())),
+        ("dns", "DNS 解析", timings.dns),
+        ("connect", "TCP 连接", timings.connect),
+        ("tls", "TLS 握手", timings.tls),
+        ("wait", "等待首字节", Some(timings.wait)),
+        ("receive", "下载", Some(timings.receive)),
+    ];
+    let sum: f64 = phases.iter().filter_map(|(_, _, duration)| *duration).map(|duration| duration.as_secs_f64()).sum();
+    let scale = sum.max(timings.total.as_secs_f64()).max(f64::EPSILON);
+    let mut start = 0.0;
+    phases
+        .into_iter()
+        .filter_map(|(kind, name, duration)| {
+            let seconds = duration?.as_secs_f64();
+            let phase = TimingPhase {
+                kind: SharedString::from(kind),
+                name: SharedString::from(name),
+                start: (start / scale) as f32,
+                length: (seconds / scale) as f32,
+                duration: SharedString::from(format!("{:.1} ms", seconds * 1000.0)),
+            };
+            start += seconds;
+            Some(phase)
+        })
+        .collect()
+}
+
+// Text shown in the log detail pane; long or binary bodies are summarized
+fn body_preview(body: &[u8], size: u64) -> String {
+    const PREVIEW_LIMIT: usize = 64 * 1024;
+
+    let text = match std::str::from_utf8(body) {
+        Ok(text) => text,
+        // The capture may end in the middle of a character
+        Err(e) if e.error_len().is_none() => std::str::from_utf8(&body[..e.valid_up_to()]).unwrap_or_default(),
+        Err(_) => return format!("<{} 二进制内容>", format_size(size)),
+    };
+    if text.len() as u64 == size && text.len() <= PREVIEW_LIMIT {
+        return text.to_string();
+    }
+
+    let mut end = text.len().min(PREVIEW_LIMIT);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}\n…（仅显示前 {}，共 {}）", &text[..end], format_size(end as u64), format_size(size))
+}
+
+fn format_size(size: u64) -> String {
+    match size {
+        0..1024 => format!("{} B", size),
+        1024..1_048_576 => format!("{:.1} KB", size as f64 / 1024.0),
+        _ => format!("{:.1} MB", size as f64 / 1_048_576.0),
+    }
+}
+
+fn header_rewrite(rule: &HeaderRule) -> HeaderRewrite {
+    HeaderRewrite {
+        id: rule.id.to_string(),
+        enabled: rule.enabled,
+        domain: rule.domain.to_string(),
+        path: rule.path.to_string(),
+        phase: rule.phase.to_string(),
+        action: rule.action.to_string(),
+        name: rule.name.to_string(),
+        value: rule.value.to_string(),
+    }
+}
+
+fn update_backend_header_rules(server: &Arc<ProxyServer>, model: &Rc<VecModel<HeaderRule>>) {
+    server.update_header_rewrites(model.iter().map(|rule| header_rewrite(&rule)).collect());
+}
+
+fn backend_breakpoint(breakpoint: &BreakpointRule) -> Breakpoint {
+    Breakpoint {
+        id: breakpoint.id.to_string(),
+        enabled: breakpoint.enabled,
+        domain: breakpoint.domain.to_string(),
+        path: breakpoint.path.to_string(),
+        on_request: breakpoint.on_request,
+        on_response: breakpoint.on_response,
+    }
+}
+
+fn update_backend_breakpoints(server: &Arc<ProxyServer>, model: &Rc<VecModel<BreakpointRule>>) {
+    server.update_breakpoints(model.iter().map(|breakpoint| backend_breakpoint(&breakpoint)).collect());
+}
+
+fn dns_override(rule: &DnsRule) -> DnsOverride {
+    DnsOverride {
+        id: rule.id.to_string(),
+        // Entries of a switched-off profile are off too
+        enabled: rule.enabled && rule.profile_enabled,
+        domain: rule.domain.to_string(),
+        address: rule.address.to_string(),
+        profile: rule.profile.to_string(),
+    }
+}
+
+fn update_backend_dns_overrides(server: &Arc<ProxyServer>, model: &Rc<VecModel<DnsRule>>) {
+    server.update_dns_overrides(model.iter().map(|rule| dns_override(&rule)).collect());
+}
+
+// Profiles in the order they first appear, with their entry counts
+fn dns_profiles(model: &Rc<VecModel<DnsRule>>) -> Vec<DnsProfile> {
+    let mut profiles: Vec<DnsProfile> = Vec::new();
+    for rule in model.iter().filter(|rule| !rule.profile.is_empty()) {
+        match profiles.iter_mut().find(|profile| profile.name == rule.profile) {
+            Some(profile) => profile.count += 1,
+            None => profiles.push(DnsProfile {
+                name: rule.profile.clone(),
+                enabled: rule.profile_enabled,
+                count: 1,
+            }),
+        }
+    }
+    profiles
+}
+
 /// 配置 macOS 窗口的原生标题栏样式
 fn app_data_dir() -> PathBuf {
     if let Some(mut dir) = dirs::config_dir() {
@@ -669,13 +1663,79 @@ fn load_rules_into_model(model: &Rc<VecModel<ProxyRule>>) {
     }
 
     for rule in store.rules {
-        model.push(ProxyRule {
+        let mut row = ProxyRule {
             id: SharedString::from(rule.id),
             domain: SharedString::from(rule.domain),
+            domain_regex: rule.domain_regex,
+            path: SharedString::from(rule.path),
+            path_mode: SharedString::from(rule.path_mode),
+            rewrite_path: rule.rewrite_path,
+            path_rewrite: SharedString::from(rule.path_rewrite),
             target: SharedString::from(rule.target),
             protocol: SharedString::from(rule.protocol),
             enabled: rule.enabled,
-        });
+            skip_verify: rule.skip_verify,
+            cors_origins: SharedString::from(rule.cors_origins),
+            action: SharedString::from(rule.action),
+            local_file: SharedString::from(rule.local_file),
+            local_body: SharedString::from(rule.local_body),
+            local_status: SharedString::from(rule.local_status.to_string()),
+            local_headers: SharedString::from(rule.local_headers),
+            local_content_type: SharedString::from(rule.local_content_type),
+            remote_url: SharedString::from(rule.remote_url),
+            keep_host: rule.keep_host,
+            throttle: SharedString::from(rule.throttle),
+            upstream_proxy: SharedString::from(rule.upstream_proxy),
+            ws_drop: SharedString::from(rule.ws_drop),
+            balance: SharedString::from(rule.balance),
+            health_path: SharedString::from(rule.health_path),
+            health_interval: SharedString::from(rule.health_interval.to_string()),
+            health: Default::default(),
+        };
+        row.health = target_health(&row, None);
+        model.push(row);
+    }
+}
+
+// One entry per target of a rule with health checks, none otherwise. The
+// server keeps a rule's health state while its targets and checks are
+// unchanged, so `previous` results carry over in that case.
+fn target_health(rule: &ProxyRule, previous: Option<&ProxyRule>) -> slint::ModelRc<TargetStatus> {
+    if rule.action != "forward" || rule.health_path.trim().is_empty() {
+        return Default::default();
+    }
+    let unchanged = previous.filter(|old| {
+        old.action == rule.action
+            && old.target == rule.target
+            && old.balance == rule.balance
+            && old.health_path.trim() == rule.health_path.trim()
+            && old.health_interval.trim() == rule.health_interval.trim()
+    });
+    if let Some(old) = unchanged {
+        return old.health.clone();
+    }
+
+    let targets = balance::parse_targets(&rule.target).unwrap_or_default();
+    let statuses: Vec<TargetStatus> = targets
+        .into_iter()
+        .map(|(target, _)| TargetStatus {
+            target: SharedString::from(target),
+            state: SharedString::new(),
+        })
+        .collect();
+    Rc::new(VecModel::from(statuses)).into()
+}
+
+// Show a health check result on the rules page
+fn show_target_health(ui: &AppWindow, event: HealthEvent) {
+    let rules = ui.get_rules();
+    for rule in rules.iter().filter(|rule| rule.id == event.rule_id.as_str()) {
+        for (i, mut status) in rule.health.iter().enumerate() {
+            if status.target == event.target.as_str() {
+                status.state = SharedString::from(if event.up { "up" } else { "down" });
+                rule.health.set_row_data(i, status);
+            }
+        }
     }
 }
 
@@ -685,13 +1745,154 @@ fn persist_rules(model: &Rc<VecModel<ProxyRule>>) {
         rules.push(PersistedRule {
             id: rule.id.to_string(),
             domain: rule.domain.to_string(),
+            domain_regex: rule.domain_regex,
+            path: rule.path.to_string(),
+            path_mode: rule.path_mode.to_string(),
+            rewrite_path: rule.rewrite_path,
+            path_rewrite: rule.path_rewrite.to_string(),
             target: rule.target.to_string(),
             protocol: rule.protocol.to_string(),
             enabled: rule.enabled,
+            skip_verify: rule.skip_verify,
+            cors_origins: rule.cors_origins.to_string(),
+            action: rule.action.to_string(),
+            local_file: rule.local_file.to_string(),
+            local_body: rule.local_body.to_string(),
+            local_status: rule.local_status.parse().unwrap_or(200),
+            local_headers: rule.local_headers.to_string(),
+            local_content_type: rule.local_content_type.to_string(),
+            remote_url: rule.remote_url.to_string(),
+            keep_host: rule.keep_host,
+            throttle: rule.throttle.to_string(),
+            upstream_proxy: rule.upstream_proxy.trim().to_string(),
+            ws_drop: rule.ws_drop.trim().to_string(),
+            balance: rule.balance.to_string(),
+            health_path: rule.health_path.trim().to_string(),
+            health_interval: rule.health_interval.trim().parse().unwrap_or(DEFAULT_HEALTH_INTERVAL),
+        });
+    }
+
+    let mut store = load_rules_store();
+    store.rules = rules;
+    write_rules_store(&store);
+}
+
+fn load_header_rules_into_model(model: &Rc<VecModel<HeaderRule>>) {
+    let store = load_rules_store();
+
+    while model.row_count() > 0 {
+        model.remove(0);
+    }
+
+    for rule in store.header_rules {
+        model.push(HeaderRule {
+            id: SharedString::from(rule.id),
+            enabled: rule.enabled,
+            domain: SharedString::from(rule.domain),
+            path: SharedString::from(rule.path),
+            phase: SharedString::from(rule.phase),
+            action: SharedString::from(rule.action),
+            name: SharedString::from(rule.name),
+            value: SharedString::from(rule.value),
         });
     }
+}
 
-    let store = RuleStore { rules };
+fn persist_header_rules(model: &Rc<VecModel<HeaderRule>>) {
+    let mut store = load_rules_store();
+    store.header_rules = model
+        .iter()
+        .map(|rule| PersistedHeaderRule {
+            id: rule.id.to_string(),
+            enabled: rule.enabled,
+            domain: rule.domain.to_string(),
+            path: rule.path.to_string(),
+            phase: rule.phase.to_string(),
+            action: rule.action.to_string(),
+            name: rule.name.to_string(),
+            value: rule.value.to_string(),
+        })
+        .collect();
+    write_rules_store(&store);
+}
+
+fn load_breakpoints_into_model(model: &Rc<VecModel<BreakpointRule>>) {
+    let store = load_rules_store();
+
+    while model.row_count() > 0 {
+        model.remove(0);
+    }
+
+    for breakpoint in store.breakpoints {
+        model.push(BreakpointRule {
+            id: SharedString::from(breakpoint.id),
+            enabled: breakpoint.enabled,
+            domain: SharedString::from(breakpoint.domain),
+            path: SharedString::from(breakpoint.path),
+            on_request: breakpoint.on_request,
+            on_response: breakpoint.on_response,
+        });
+    }
+}
+
+fn persist_breakpoints(model: &Rc<VecModel<BreakpointRule>>) {
+    let mut store = load_rules_store();
+    store.breakpoints = model
+        .iter()
+        .map(|breakpoint| PersistedBreakpoint {
+            id: breakpoint.id.to_string(),
+            enabled: breakpoint.enabled,
+            domain: breakpoint.domain.to_string(),
+            path: breakpoint.path.to_string(),
+            on_request: breakpoint.on_request,
+            on_response: breakpoint.on_response,
+        })
+        .collect();
+    write_rules_store(&store);
+}
+
+fn load_dns_rules_into_model(model: &Rc<VecModel<DnsRule>>) {
+    let store = load_rules_store();
+
+    while model.row_count() > 0 {
+        model.remove(0);
+    }
+
+    for entry in store.dns_overrides {
+        let profile_enabled = !store.disabled_dns_profiles.contains(&entry.profile);
+        model.push(DnsRule {
+            id: SharedString::from(entry.id),
+            enabled: entry.enabled,
+            domain: SharedString::from(entry.domain),
+            address: SharedString::from(entry.address),
+            profile: SharedString::from(entry.profile),
+            profile_enabled,
+        });
+    }
+}
+
+fn persist_dns_rules(model: &Rc<VecModel<DnsRule>>) {
+    let mut store = load_rules_store();
+    store.dns_overrides = model
+        .iter()
+        .map(|rule| PersistedDnsOverride {
+            id: rule.id.to_string(),
+            enabled: rule.enabled,
+            domain: rule.domain.to_string(),
+            address: rule.address.to_string(),
+            profile: rule.profile.to_string(),
+        })
+        .collect();
+    store.disabled_dns_profiles = dns_profiles(model)
+        .into_iter()
+        .filter(|profile| !profile.enabled)
+        .map(|profile| profile.name.to_string())
+        .collect();
+    write_rules_store(&store);
+}
+
+#[allow(clippy::collapsible_if)]
+fn write_rules_store(store: &RuleStore) {
     let path = rules_store_path();
 
     if let Some(dir) = path.parent() {
@@ -701,7 +1902,7 @@ fn persist_rules(model: &Rc<VecModel<ProxyRule>>) {
         }
     }
 
-    match serde_json::to_string_pretty(&store) {
+    match serde_json::to_string_pretty(store) {
         Ok(content) => {
             if let Err(err) = fs::write(&path, content) {
                 eprintln!("Failed to write rules store {:?}: {}", path, err);
@@ -713,6 +1914,139 @@ fn persist_rules(model: &Rc<VecModel<ProxyRule>>) {
     }
 }
 
+// Backend settings, plus an error message when the upstream proxy or bypass
+// list is invalid; the broken part is left out until it is fixed
+fn proxy_settings(settings: &AppSettings) -> (ProxySettings, String) {
+    let mut errors = Vec::new();
+    let upstream_proxy = match settings.upstream_proxy.as_str() {
+        "" => None,
+        url => UpstreamProxy::parse(url).map_err(|e| errors.push(e)).ok(),
+    };
+    let proxy_bypass = Bypass::parse(&settings.proxy_bypass)
+        .map_err(|e| errors.push(e))
+        .unwrap_or_default();
+
+    let backend = ProxySettings {
+        intercept_https: settings.intercept_https,
+        auto_issue_certs: settings.auto_issue_certs,
+        cors_enabled: settings.cors_enabled,
+        throttle_profile: settings.throttle_profile.clone(),
+        custom_throttle: Conditions {
+            down_kbps: settings.throttle_down_kbps,
+            up_kbps: settings.throttle_up_kbps,
+            latency_ms: settings.throttle_latency_ms,
+            drop_percent: settings.throttle_drop_percent,
+        },
+        upstream_proxy,
+        proxy_bypass,
+        // A list that doesn't parse must not open the proxy to everyone
+        client_allowlist: Allowlist::parse(&settings.client_allowlist).unwrap_or_default(),
+        proxy_auth: Credentials::new(&settings.proxy_username, &settings.proxy_password),
+        // Queries that can't be forwarded are refused, see `dns_error`
+        dns_upstream: nameserver::parse_upstream(&settings.dns_upstream).unwrap_or(None),
+        // Until the list parses, nothing is exempted, see `pac_error`
+        pac_bypass: Bypass::parse(&settings.pac_bypass).unwrap_or_default(),
+    };
+    (backend, errors.join("；"))
+}
+
+// Empty means loopback only
+fn parse_bind_address(text: &str) -> Result<std::net::IpAddr, String> {
+    let text = strip_brackets(text.trim());
+    if text.is_empty() {
+        return Ok(std::net::Ipv4Addr::LOCALHOST.into());
+    }
+    text.parse()
+        .map_err(|_| format!("监听地址无效: {}（应为 IP 地址，例如 127.0.0.1、0.0.0.0 或 ::）", text))
+}
+
+// Problems with the bind address and the client allowlist
+fn listen_error(settings: &AppSettings) -> String {
+    let allowlist = Allowlist::parse(&settings.client_allowlist);
+    // Listening beyond loopback is pointless until someone else is allowed in
+    let unreachable = parse_bind_address(&settings.bind_address).is_ok_and(|bind| !bind.is_loopback())
+        && allowlist.as_ref().is_ok_and(Allowlist::is_empty);
+    let errors: Vec<String> = [
+        parse_bind_address(&settings.bind_address).err(),
+        allowlist.err().map(|e| format!("客户端白名单无效，仅允许本机连接：{}", e)),
+        unreachable.then(|| "客户端白名单为空，仅允许本机连接；请填写允许的地址，例如 192.168.1.0/24".to_string()),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    errors.join("；")
+}
+
+fn dns_error(settings: &AppSettings) -> String {
+    nameserver::parse_upstream(&settings.dns_upstream)
+        .err()
+        .map(|e| format!("{}，其他域名的查询将被拒绝", e))
+        .unwrap_or_default()
+}
+
+fn pac_error(settings: &AppSettings) -> String {
+    Bypass::parse(&settings.pac_bypass)
+        .err()
+        .map(|e| format!("{}，PAC 脚本暂不排除任何地址", e))
+        .unwrap_or_default()
+}
+
+// The address other devices on the LAN most likely reach this machine at.
+// Connecting a UDP socket only picks a route; nothing is sent.
+fn lan_address() -> Option<std::net::IpAddr> {
+    let socket = std::net::UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect("192.0.2.1:80").ok()?;
+    let addr = socket.local_addr().ok()?.ip();
+    (!addr.is_unspecified() && !addr.is_loopback()).then_some(addr)
+}
+
+fn settings_store_path() -> PathBuf {
+    app_data_dir().join("settings.json")
+}
+
+fn load_settings() -> AppSettings {
+    let path = settings_store_path();
+    if !path.exists() {
+        return AppSettings::default();
+    }
+
+    match fs::read_to_string(&path) {
+        Ok(content) => match serde_json::from_str::<AppSettings>(&content) {
+            Ok(settings) => settings,
+            Err(err) => {
+                eprintln!("Failed to parse settings {:?}: {}", path, err);
+                AppSettings::default()
+            }
+        },
+        Err(err) => {
+            eprintln!("Failed to read settings {:?}: {}", path, err);
+            AppSettings::default()
+        }
+    }
+}
+
+fn persist_settings(settings: &AppSettings) {
+    let path = settings_store_path();
+
+    if let Some(dir) = path.parent()
+        && let Err(err) = fs::create_dir_all(dir)
+    {
+        eprintln!("Failed to create settings directory {:?}: {}", dir, err);
+        return;
+    }
+
+    match serde_json::to_string_pretty(settings) {
+        Ok(content) => {
+            if let Err(err) = fs::write(&path, content) {
+                eprintln!("Failed to write settings {:?}: {}", path, err);
+            }
+        }
+        Err(err) => {
+            eprintln!("Failed to serialize settings {:?}: {}", path, err);
+        }
+    }
+}
+
 fn is_valid_port(target: &str) -> bool {
     if target.is_empty() {
         return false;
@@ -724,6 +2058,42 @@ fn is_valid_port(target: &str) -> bool {
     }
 }
 
+/// 规则目标：纯端口（转发到本机），或 host / host:port
+fn is_valid_target(target: &str) -> bool {
+    let target = target.trim();
+    if target.chars().all(|c| c.is_ascii_digit()) {
+        return is_valid_port(target);
+    }
+
+    let (host, port) = if let Some(rest) = target.strip_prefix('[') {
+        // [IPv6]:port
+        match rest.split_once(']') {
+            Some((host, "")) => (host, None),
+            Some((host, port)) => match port.strip_prefix(':') {
+                Some(port) => (host, Some(port)),
+                None => return false,
+            },
+            None => return false,
+        }
+    } else {
+        match target.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (target, None),
+        }
+    };
+
+    if let Some(port) = port
+        && !is_valid_port(port)
+    {
+        return false;
+    }
+
+    !host.is_empty()
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | ':'))
+}
+
 #[cfg(target_os = "macos")]
 fn configure_macos_titlebar(ui: &AppWindow) {
     // use i_slint_backend_winit::winit::platform::macos::WindowExtMacOS;
@@ -791,6 +2161,7 @@ fn load_hosts_entries(model: &Rc<VecModel<HostEntry>>) -> Result<(), Box<dyn Err
     Ok(())
 }
 
+#[allow(clippy::collapsible_if)]
 async fn send_http_request(
     method: &str,
     url: &str,
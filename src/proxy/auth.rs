@@ -0,0 +1,55 @@
+use std::fmt;
+
+use base64::Engine;
+use hyper::header::HeaderValue;
+
+// Sent in `Proxy-Authenticate` with 407 responses
+pub const CHALLENGE: &str = "Basic realm=\"Ovo\", charset=\"UTF-8\"";
+
+// Username and password clients must present to use the proxy
+#[derive(Clone, PartialEq)]
+pub struct Credentials {
+    username: String,
+    password: String,
+}
+
+impl Credentials {
+    // None when no username is set, i.e. authentication is off
+    pub fn new(username: &str, password: &str) -> Option<Self> {
+        (!username.is_empty()).then(|| Self {
+            username: username.to_string(),
+            password: password.to_string(),
+        })
+    }
+
+    pub fn matches(&self, username: &[u8], password: &[u8]) -> bool {
+        // Both are compared in full, so the time taken doesn't tell which was wrong
+        let username_ok = constant_time_eq(self.username.as_bytes(), username);
+        let password_ok = constant_time_eq(self.password.as_bytes(), password);
+        username_ok & password_ok
+    }
+}
+
+// Settings are printed when they change, so the password is left out
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+// Username and password from a `Basic` Proxy-Authorization value
+pub fn basic_credentials(value: &HeaderValue) -> Option<(String, String)> {
+    let (scheme, encoded) = value.to_str().ok()?.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = base64::engine::general_purpose::STANDARD.decode(encoded.trim()).ok()?;
+    let (username, password) = String::from_utf8(decoded).ok()?.split_once(':').map(|(u, p)| (u.to_string(), p.to_string()))?;
+    Some((username, password))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
@@ -0,0 +1,170 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Seconds between health checks when a rule doesn't say
+pub const DEFAULT_HEALTH_INTERVAL: u32 = 10;
+
+// A target that doesn't answer its health check within this long is down
+pub const HEALTH_TIMEOUT: Duration = Duration::from_secs(5);
+
+const MAX_WEIGHT: u32 = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Strategy {
+    RoundRobin,
+    // The target with the fewest requests in flight
+    LeastConnections,
+    // Round-robin where each target gets `weight` turns
+    Weighted,
+}
+
+impl Strategy {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name.trim() {
+            "" | "round-robin" => Ok(Strategy::RoundRobin),
+            "least-conn" => Ok(Strategy::LeastConnections),
+            "weighted" => Ok(Strategy::Weighted),
+            other => Err(format!("未知的负载均衡策略: {}", other)),
+        }
+    }
+}
+
+// Split a rule's target list, e.g. `3000 weight=3, 3001`, into targets and
+// their weights (1 unless given)
+pub fn parse_targets(text: &str) -> Result<Vec<(String, u32)>, String> {
+    let mut targets = Vec::new();
+    for entry in text.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let mut parts = entry.split_whitespace();
+        let target = parts.next().unwrap_or_default().to_string();
+        let mut weight = 1;
+        for option in parts {
+            weight = option
+                .strip_prefix("weight=")
+                .and_then(|weight| weight.parse().ok())
+                .filter(|weight| (1..=MAX_WEIGHT).contains(weight))
+                .ok_or_else(|| format!("目标 {} 的权重无效: {}（应为 weight=1 到 weight={}）", target, option, MAX_WEIGHT))?;
+        }
+        targets.push((target, weight));
+    }
+    Ok(targets)
+}
+
+// Health of one target, as reported to the UI
+#[derive(Clone, Debug)]
+pub struct HealthEvent {
+    pub rule_id: String,
+    pub target: String,
+    pub up: bool,
+}
+
+#[derive(Debug)]
+pub struct Backend {
+    // As written in the rule, without a default port filled in
+    pub target: String,
+    weight: u32,
+    up: AtomicBool,
+    checked: AtomicBool,
+    active: AtomicUsize,
+}
+
+impl Backend {
+    pub fn is_up(&self) -> bool {
+        self.up.load(Ordering::Relaxed)
+    }
+
+    // Record a health check result; true when it is news to the UI
+    pub fn set_up(&self, up: bool) -> bool {
+        let was_up = self.up.swap(up, Ordering::Relaxed);
+        let first = !self.checked.swap(true, Ordering::Relaxed);
+        first || was_up != up
+    }
+}
+
+// A request in flight to a backend, counted for least-connections until dropped
+#[derive(Debug)]
+pub struct Lease(Arc<Backend>);
+
+impl Lease {
+    pub fn target(&self) -> &str {
+        &self.0.target
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Spreads a rule's requests over its targets. Targets start out up and are
+// only taken out of rotation by failed health checks.
+#[derive(Debug)]
+pub struct Balancer {
+    strategy: Strategy,
+    backends: Vec<Arc<Backend>>,
+    next: AtomicUsize,
+}
+
+impl Balancer {
+    pub fn new(targets: Vec<(String, u32)>, strategy: Strategy) -> Self {
+        let backends = targets
+            .into_iter()
+            .map(|(target, weight)| {
+                Arc::new(Backend {
+                    target,
+                    weight,
+                    up: AtomicBool::new(true),
+                    checked: AtomicBool::new(false),
+                    active: AtomicUsize::new(0),
+                })
+            })
+            .collect();
+        Self {
+            strategy,
+            backends,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn backends(&self) -> &[Arc<Backend>] {
+        &self.backends
+    }
+
+    // The target for the next request; None only without any targets
+    pub fn pick(&self) -> Option<Lease> {
+        let up: Vec<&Arc<Backend>> = self.backends.iter().filter(|backend| backend.is_up()).collect();
+        // With every target down, keep trying them all rather than failing outright
+        let candidates = if up.is_empty() { self.backends.iter().collect() } else { up };
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let turn = self.next.fetch_add(1, Ordering::Relaxed);
+        let backend = match self.strategy {
+            Strategy::RoundRobin => candidates[turn % candidates.len()],
+            // Ties go to whoever is next in turn, so idle targets still alternate
+            Strategy::LeastConnections => (0..candidates.len())
+                .map(|i| candidates[(turn + i) % candidates.len()])
+                .min_by_key(|backend| backend.active.load(Ordering::Relaxed))
+                .unwrap_or(candidates[0]),
+            Strategy::Weighted => {
+                let total: usize = candidates.iter().map(|backend| backend.weight as usize).sum();
+                let mut slot = turn % total;
+                candidates
+                    .iter()
+                    .find(|backend| match slot.checked_sub(backend.weight as usize) {
+                        Some(rest) => {
+                            slot = rest;
+                            false
+                        }
+                        None => true,
+                    })
+                    .copied()
+                    .unwrap_or(candidates[0])
+            }
+        };
+        backend.active.fetch_add(1, Ordering::Relaxed);
+        Some(Lease(backend.clone()))
+    }
+}
@@ -0,0 +1,295 @@
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use http_body_util::combinators::BoxBody;
+use http_body_util::BodyExt;
+use hyper::body::{Body, Frame, SizeHint};
+use hyper::header::{self, HeaderMap};
+use tokio::sync::{mpsc, oneshot};
+
+use super::headers::Phase;
+use super::local;
+use super::matcher::{DomainPattern, PathPattern};
+
+// Paused messages nobody decides on are released unmodified after this long
+pub const PAUSE_TIMEOUT: Duration = Duration::from_secs(120);
+
+// Messages with larger bodies pass unpaused rather than being held in memory
+pub const MAX_PAUSED_BODY: usize = 1024 * 1024;
+
+#[derive(Clone, Debug)]
+pub struct Breakpoint {
+    pub id: String,
+    pub enabled: bool,
+    // Same domain syntax as proxy rules; the path is a prefix, empty = any
+    pub domain: String,
+    pub path: String,
+    pub on_request: bool,
+    pub on_response: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct CompiledBreakpoint {
+    domain: DomainPattern,
+    path: PathPattern,
+    on_request: bool,
+    on_response: bool,
+}
+
+impl CompiledBreakpoint {
+    pub fn compile(breakpoint: &Breakpoint) -> Result<Self, String> {
+        Ok(Self {
+            domain: DomainPattern::parse(&breakpoint.domain, false)?,
+            path: PathPattern::parse(&breakpoint.path, "prefix")?,
+            on_request: breakpoint.on_request,
+            on_response: breakpoint.on_response,
+        })
+    }
+}
+
+pub fn matches(breakpoints: &[CompiledBreakpoint], phase: Phase, host: &str, path: &str) -> bool {
+    breakpoints.iter().any(|breakpoint| {
+        let phase = match phase {
+            Phase::Request => breakpoint.on_request,
+            Phase::Response => breakpoint.on_response,
+        };
+        phase && breakpoint.domain.matches(host) && breakpoint.path.matches(path)
+    })
+}
+
+// A request or response waiting for the user, in editable form
+#[derive(Clone, Debug)]
+pub struct PausedMessage {
+    pub id: u64,
+    pub phase: Phase,
+    pub method: String,
+    pub url: String,
+    // Only meaningful for responses
+    pub status: u16,
+    // One `Name: value` header per line
+    pub headers: String,
+    pub body: String,
+    // Bodies that aren't UTF-8 are shown as a summary and can't be edited
+    pub binary: bool,
+}
+
+impl PausedMessage {
+    pub fn new(phase: Phase, method: String, url: String, status: u16, headers: &HeaderMap, body: &Bytes) -> Self {
+        let (body, binary) = match std::str::from_utf8(body) {
+            Ok(text) => (text.to_string(), false),
+            Err(_) => (format!("<{} 字节二进制内容，无法编辑>", body.len()), true),
+        };
+
+        Self {
+            id: 0,
+            phase,
+            method,
+            url,
+            status,
+            headers: format_headers(headers),
+            body,
+            binary,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum BreakpointEvent {
+    Paused(PausedMessage),
+    // Timed out before the user decided
+    Released(u64),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Resolution {
+    // Send the (edited) message on
+    Continue,
+    // Drop the request and answer with an error
+    Abort,
+    // Answer with the edited status, headers and body instead
+    Respond,
+}
+
+#[derive(Clone, Debug)]
+pub struct Decision {
+    pub resolution: Resolution,
+    pub method: String,
+    pub url: String,
+    pub status: u16,
+    pub headers: String,
+    pub body: String,
+}
+
+impl Decision {
+    // Edited headers, falling back to `original` if they don't parse
+    pub fn header_map(&self, original: &HeaderMap) -> HeaderMap {
+        match local::parse_headers(&self.headers) {
+            Ok(mut headers) => {
+                // The body may have changed size; hyper recomputes the framing
+                headers.remove(header::CONTENT_LENGTH);
+                headers.remove(header::TRANSFER_ENCODING);
+                headers
+            }
+            Err(e) => {
+                println!("Ignoring edited headers: {}", e);
+                original.clone()
+            }
+        }
+    }
+
+    // Edited body, unless the original was binary and shown as a summary
+    pub fn body_bytes(&self, original: Bytes, binary: bool) -> Bytes {
+        if binary {
+            original
+        } else {
+            Bytes::from(self.body.clone())
+        }
+    }
+}
+
+// Clears a paused message once `pause` is done with it, including when the
+// request is dropped mid-wait because its client went away
+struct PauseGuard<'a> {
+    hub: &'a BreakpointHub,
+    id: u64,
+    // Whether the UI lists the message and has to be told it is gone
+    notified: bool,
+}
+
+impl Drop for PauseGuard<'_> {
+    fn drop(&mut self) {
+        let undecided = self.hub.pending.lock().map(|mut pending| pending.remove(&self.id).is_some()).unwrap_or(false);
+        if !undecided || !self.notified {
+            return;
+        }
+        println!("Breakpoint {} released without a decision, continuing", self.id);
+        // Told in the background, as drop can't wait on the channel
+        let (events, id) = (self.hub.events.clone(), self.id);
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            handle.spawn(async move {
+                let _ = events.send(BreakpointEvent::Released(id)).await;
+            });
+        }
+    }
+}
+
+// Paused messages waiting for a decision from the UI
+pub struct BreakpointHub {
+    next_id: AtomicU64,
+    pending: Mutex<HashMap<u64, oneshot::Sender<Decision>>>,
+    events: mpsc::Sender<BreakpointEvent>,
+}
+
+impl BreakpointHub {
+    pub fn new(events: mpsc::Sender<BreakpointEvent>) -> Self {
+        Self {
+            next_id: AtomicU64::new(1),
+            pending: Mutex::new(HashMap::new()),
+            events,
+        }
+    }
+
+    // Hand `message` to the UI and wait for the user. None means it timed out,
+    // or the UI was gone or too busy to be told, and the message should pass
+    // through unmodified.
+    pub async fn pause(&self, mut message: PausedMessage) -> Option<Decision> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        message.id = id;
+
+        let (decision_tx, decision_rx) = oneshot::channel();
+        if let Ok(mut pending) = self.pending.lock() {
+            pending.insert(id, decision_tx);
+        }
+
+        let mut guard = PauseGuard { hub: self, id, notified: false };
+        // Without waiting on the channel, so only the timeout can hold the message up
+        guard.notified = self.events.try_send(BreakpointEvent::Paused(message)).is_ok();
+        if !guard.notified {
+            println!("Breakpoint {} could not be shown, continuing", id);
+            return None;
+        }
+        tokio::time::timeout(PAUSE_TIMEOUT, decision_rx).await.ok().and_then(Result::ok)
+    }
+
+    pub fn resolve(&self, id: u64, decision: Decision) -> bool {
+        let sender = self.pending.lock().ok().and_then(|mut pending| pending.remove(&id));
+        match sender {
+            Some(sender) => sender.send(decision).is_ok(),
+            None => false,
+        }
+    }
+}
+
+// Read a body to pause its message. A body larger than `MAX_PAUSED_BODY`
+// comes back as `Err`, made of what was read so far and the rest, to pass on
+// unchanged. Trailers are dropped either way.
+pub async fn read_body(
+    mut body: BoxBody<Bytes, hyper::Error>,
+) -> Result<Result<Bytes, BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+    if body.size_hint().lower() > MAX_PAUSED_BODY as u64 {
+        return Ok(Err(body));
+    }
+
+    let mut read = VecDeque::new();
+    let mut len = 0;
+    while let Some(frame) = body.frame().await {
+        let Ok(data) = frame?.into_data() else {
+            continue;
+        };
+        len += data.len();
+        read.push_back(data);
+        if len > MAX_PAUSED_BODY {
+            return Ok(Err(Prefixed { read, rest: body }.boxed()));
+        }
+    }
+
+    let mut buffered = BytesMut::with_capacity(len);
+    read.iter().for_each(|data| buffered.extend_from_slice(data));
+    Ok(Ok(buffered.freeze()))
+}
+
+// The data already read from a body, followed by the rest of it
+struct Prefixed {
+    read: VecDeque<Bytes>,
+    rest: BoxBody<Bytes, hyper::Error>,
+}
+
+impl Body for Prefixed {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, hyper::Error>>> {
+        match self.read.pop_front() {
+            Some(data) => Poll::Ready(Some(Ok(Frame::data(data)))),
+            None => Pin::new(&mut self.rest).poll_frame(cx),
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.read.is_empty() && self.rest.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        let read: u64 = self.read.iter().map(|data| data.len() as u64).sum();
+        let rest = self.rest.size_hint();
+        let mut hint = SizeHint::new();
+        hint.set_lower(rest.lower() + read);
+        if let Some(upper) = rest.upper() {
+            hint.set_upper(upper + read);
+        }
+        hint
+    }
+}
+
+pub fn format_headers(headers: &HeaderMap) -> String {
+    headers
+        .iter()
+        .map(|(name, value)| format!("{}: {}", name, String::from_utf8_lossy(value.as_bytes())))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
@@ -1,10 +1,15 @@
+use std::collections::HashMap;
 use std::fs;
 use std::path::PathBuf;
-use rcgen::{CertificateParams, DistinguishedName, DnType, KeyPair};
+use std::sync::{Arc, Mutex};
+use rcgen::{CertificateParams, DistinguishedName, DnType, ExtendedKeyUsagePurpose, Issuer, KeyPair};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::sign::CertifiedKey;
 use time::{Duration, OffsetDateTime};
 
 pub struct CertificateAuthority {
     cert_dir: PathBuf,
+    leaf_cache: Mutex<HashMap<String, Arc<CertifiedKey>>>,
 }
 
 #[derive(Debug, Clone)]
@@ -29,7 +34,10 @@ impl CertificateAuthority {
             });
         }
 
-        Self { cert_dir }
+        Self {
+            cert_dir,
+            leaf_cache: Mutex::new(HashMap::new()),
+        }
     }
 
     pub fn get_cert_dir(&self) -> PathBuf {
@@ -69,6 +77,11 @@ impl CertificateAuthority {
 
         println!("CA Certificate generated at: {:?}", cert_path);
 
+        // Leaf certificates signed by the previous CA are no longer trusted
+        if let Ok(mut cache) = self.leaf_cache.lock() {
+            cache.clear();
+        }
+
         Ok(CaInfo {
             id: "ca-root".to_string(),
             domain: "Ovo Root CA".to_string(),
@@ -101,4 +114,50 @@ impl CertificateAuthority {
             validity: "已安装".to_string(), // TODO: Parse actual validity
         })
     }
+
+    pub fn has_ca_cert(&self) -> bool {
+        self.cert_dir.join("ca.crt").exists() && self.cert_dir.join("ca.key").exists()
+    }
+
+    // Issue (or reuse) a leaf certificate for `host`, signed by the CA on disk
+    pub fn leaf_cert(&self, host: &str) -> Result<Arc<CertifiedKey>, Box<dyn std::error::Error + Send + Sync>> {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        if let Ok(cache) = self.leaf_cache.lock()
+            && let Some(key) = cache.get(&host)
+        {
+            return Ok(key.clone());
+        }
+
+        let ca_cert_pem = fs::read_to_string(self.cert_dir.join("ca.crt"))?;
+        let ca_key_pem = fs::read_to_string(self.cert_dir.join("ca.key"))?;
+        let issuer = Issuer::from_ca_cert_pem(&ca_cert_pem, KeyPair::from_pem(&ca_key_pem)?)?;
+
+        let mut params = CertificateParams::new(vec![host.clone()])?;
+        let mut dn = DistinguishedName::new();
+        dn.push(DnType::CommonName, host.as_str());
+        dn.push(DnType::OrganizationName, "Ovo Proxy");
+        params.distinguished_name = dn;
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+
+        // Backdate a little to tolerate clock skew between client and proxy
+        let now = OffsetDateTime::now_utc();
+        params.not_before = now - Duration::days(1);
+        params.not_after = now + Duration::days(365);
+
+        let key_pair = KeyPair::generate()?;
+        let cert = params.signed_by(&key_pair, &issuer)?;
+
+        let key_der = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key_pair.serialize_der()));
+        let signing_key = rustls::crypto::ring::sign::any_supported_type(&key_der)?;
+        let certified = Arc::new(CertifiedKey::new(
+            vec![CertificateDer::from(cert.der().to_vec())],
+            signing_key,
+        ));
+
+        if let Ok(mut cache) = self.leaf_cache.lock() {
+            cache.insert(host, certified.clone());
+        }
+
+        Ok(certified)
+    }
 }
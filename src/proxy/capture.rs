@@ -0,0 +1,306 @@
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use http_body_util::BodyExt;
+use hyper::body::{Body, Frame, SizeHint};
+use hyper::header::HeaderMap;
+use hyper::{Request, Response};
+use time::OffsetDateTime;
+use tokio::sync::mpsc;
+
+use super::breakpoint::format_headers;
+use super::server::LogEvent;
+
+// Bodies are kept up to this size; anything beyond is counted but not stored.
+// With up to 1000 logs kept, two bodies each stay under about 500 MB in total.
+pub const BODY_CAPTURE_LIMIT: usize = 256 * 1024;
+
+// Everything recorded about one request/response exchange
+#[derive(Clone, Debug, Default)]
+pub struct Exchange {
+    pub client_addr: String,
+    // Id of the rule that handled the request, empty when none matched
+    pub rule_id: String,
+    // DNS override used to connect upstream, e.g. `api.example.com → 10.0.0.5`
+    pub dns_override: String,
+    // One `Name: value` header per line, as sent upstream
+    pub request_headers: String,
+    // Bodies hold at most `BODY_CAPTURE_LIMIT` bytes; the sizes are the real ones
+    pub request_body: Bytes,
+    pub request_size: u64,
+    pub response_headers: String,
+    pub response_body: Bytes,
+    pub response_size: u64,
+    // False when either side went away before the response body ended
+    pub complete: bool,
+    // When the request arrived, None only for entries built by hand
+    pub started: Option<OffsetDateTime>,
+    pub timings: Timings,
+    // Connection id of the WebSocket this request was upgraded to
+    pub websocket: Option<u64>,
+}
+
+// Where the time of one exchange went. The phases follow one another, so
+// they add up to `total`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Timings {
+    // From receiving the request until it was sent upstream, minus the
+    // connection phases: rule matching, breakpoints, simulated latency and
+    // waiting for a free connection
+    pub blocked: Duration,
+    // None when no new connection was opened for the request
+    pub dns: Option<Duration>,
+    pub connect: Option<Duration>,
+    pub tls: Option<Duration>,
+    // From sending the request until the response headers were ready; for
+    // requests answered by the proxy itself, from receiving the request
+    pub wait: Duration,
+    // From the response headers until the body was sent on
+    pub receive: Duration,
+    pub total: Duration,
+    // Sent on a kept-alive connection
+    pub reused: bool,
+}
+
+// Time taken to open an upstream connection, by phase. DNS is not measured
+// when the connection goes through an upstream proxy.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ConnectTimings {
+    pub dns: Option<Duration>,
+    // Including the handshake with an upstream proxy
+    pub connect: Option<Duration>,
+    pub tls: Option<Duration>,
+}
+
+#[derive(Debug, Default)]
+struct Captured {
+    data: Vec<u8>,
+    size: u64,
+}
+
+// Shared buffer a `TeeBody` copies its chunks into
+#[derive(Clone, Debug, Default)]
+pub struct BodyTap(Arc<Mutex<Captured>>);
+
+impl BodyTap {
+    fn push(&self, chunk: &[u8]) {
+        if let Ok(mut captured) = self.0.lock() {
+            captured.size += chunk.len() as u64;
+            let room = BODY_CAPTURE_LIMIT.saturating_sub(captured.data.len());
+            captured.data.extend_from_slice(&chunk[..chunk.len().min(room)]);
+        }
+    }
+
+    // What was captured so far, and the total size seen
+    pub fn snapshot(&self) -> (Bytes, u64) {
+        self.0
+            .lock()
+            .map(|captured| (Bytes::copy_from_slice(&captured.data), captured.size))
+            .unwrap_or_default()
+    }
+}
+
+type OnEnd = Box<dyn FnOnce(bool) + Send + Sync>;
+
+// Passes a body through unchanged while copying it into a `BodyTap`, so
+// nothing is buffered on the way. `on_end` runs once the body finishes or is
+// dropped, with whether it was read to the end.
+pub struct TeeBody {
+    inner: BoxBody<Bytes, hyper::Error>,
+    tap: BodyTap,
+    on_end: Option<OnEnd>,
+}
+
+impl TeeBody {
+    pub fn new(inner: BoxBody<Bytes, hyper::Error>, tap: BodyTap, on_end: Option<OnEnd>) -> Self {
+        Self { inner, tap, on_end }
+    }
+
+    fn end(&mut self, complete: bool) {
+        if let Some(on_end) = self.on_end.take() {
+            on_end(complete);
+        }
+    }
+}
+
+impl Body for TeeBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, hyper::Error>>> {
+        let this = &mut *self;
+        let frame = ready!(Pin::new(&mut this.inner).poll_frame(cx));
+        match &frame {
+            Some(Ok(frame)) => {
+                if let Some(data) = frame.data_ref() {
+                    this.tap.push(data);
+                }
+            }
+            Some(Err(_)) => this.end(false),
+            None => this.end(true),
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for TeeBody {
+    fn drop(&mut self) {
+        // hyper may stop polling once `is_end_stream` says there is nothing left
+        let complete = self.inner.is_end_stream();
+        self.end(complete);
+    }
+}
+
+// One request on its way through the proxy. The log entry is sent once the
+// response body has been passed on to the client.
+#[derive(Clone)]
+pub struct Recorder {
+    pub time: String,
+    pub method: String,
+    pub protocol: String,
+    pub url: String,
+    pub client_addr: String,
+    pub rule_id: String,
+    pub dns_override: String,
+    pub websocket: Option<u64>,
+    request_headers: String,
+    request_tap: BodyTap,
+    started: Instant,
+    started_at: OffsetDateTime,
+    // When the request went upstream, and how its connection was opened
+    sent: Option<(Instant, ConnectTimings, bool)>,
+}
+
+impl Recorder {
+    pub fn new(time: String, client_addr: String) -> Self {
+        Self {
+            time,
+            method: String::new(),
+            protocol: String::new(),
+            url: String::new(),
+            client_addr,
+            rule_id: String::new(),
+            dns_override: String::new(),
+            websocket: None,
+            request_headers: String::new(),
+            request_tap: BodyTap::default(),
+            started: Instant::now(),
+            started_at: OffsetDateTime::now_utc(),
+            sent: None,
+        }
+    }
+
+    pub fn set_request_headers(&mut self, headers: &HeaderMap) {
+        self.request_headers = format_headers(headers);
+    }
+
+    // Capture the request body as it is streamed upstream
+    pub fn tee_request(&self, req: Request<BoxBody<Bytes, hyper::Error>>) -> Request<BoxBody<Bytes, hyper::Error>> {
+        req.map(|body| TeeBody::new(body, self.request_tap.clone(), None).boxed())
+    }
+
+    // For requests answered after their body was already read in full
+    pub fn set_request_body(&self, body: &Bytes) {
+        self.request_tap.push(body);
+    }
+
+    // The request is about to go upstream on a connection opened with
+    // `connection`, or a `reused` one
+    pub fn sending(&mut self, connection: ConnectTimings, reused: bool) {
+        self.sent = Some((Instant::now(), connection, reused));
+    }
+
+    fn timings(&self) -> Timings {
+        let headers = self.started.elapsed();
+        let Some((sent, connection, reused)) = self.sent else {
+            return Timings {
+                wait: headers,
+                total: headers,
+                ..Default::default()
+            };
+        };
+        let before_send = sent.duration_since(self.started);
+        let connecting = [connection.dns, connection.connect, connection.tls]
+            .into_iter()
+            .flatten()
+            .sum();
+        Timings {
+            blocked: before_send.saturating_sub(connecting),
+            dns: connection.dns,
+            connect: connection.connect,
+            tls: connection.tls,
+            wait: headers.saturating_sub(before_send),
+            receive: Duration::ZERO,
+            total: headers,
+            reused,
+        }
+    }
+
+    // Tee `resp`'s body and send the log entry for this exchange when it ends
+    pub fn finish(
+        &self,
+        log_sender: &mpsc::Sender<LogEvent>,
+        status: String,
+        upstream_url: String,
+        resp: Response<BoxBody<Bytes, hyper::Error>>,
+    ) -> Response<BoxBody<Bytes, hyper::Error>> {
+        let timings = self.timings();
+        let response_tap = BodyTap::default();
+        let mut event = LogEvent {
+            time: self.time.clone(),
+            method: self.method.clone(),
+            protocol: self.protocol.clone(),
+            url: self.url.clone(),
+            status,
+            status_code: resp.status().as_u16() as i32,
+            upstream_url,
+            exchange: None,
+        };
+        let mut exchange = Exchange {
+            client_addr: self.client_addr.clone(),
+            rule_id: self.rule_id.clone(),
+            dns_override: self.dns_override.clone(),
+            request_headers: self.request_headers.clone(),
+            response_headers: format_headers(resp.headers()),
+            started: Some(self.started_at),
+            timings,
+            websocket: self.websocket,
+            ..Default::default()
+        };
+
+        let request_tap = self.request_tap.clone();
+        let started = self.started;
+        let log_sender = log_sender.clone();
+        let on_end: OnEnd = Box::new({
+            let response_tap = response_tap.clone();
+            move |complete| {
+                (exchange.request_body, exchange.request_size) = request_tap.snapshot();
+                (exchange.response_body, exchange.response_size) = response_tap.snapshot();
+                exchange.complete = complete;
+                exchange.timings.total = started.elapsed();
+                exchange.timings.receive = exchange.timings.total.saturating_sub(timings.total);
+                event.exchange = Some(exchange);
+                // May run from a destructor, so don't wait on the channel here
+                if let Ok(handle) = tokio::runtime::Handle::try_current() {
+                    handle.spawn(async move {
+                        let _ = log_sender.send(event).await;
+                    });
+                }
+            }
+        });
+
+        resp.map(|body| TeeBody::new(body, response_tap, Some(on_end)).boxed())
+    }
+}
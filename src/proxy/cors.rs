@@ -0,0 +1,85 @@
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::Method;
+
+// An origin a response is opened to
+#[derive(Clone, Debug)]
+pub struct Grant {
+    pub origin: HeaderValue,
+    // Only origins listed by name may make credentialed requests; otherwise
+    // any website could read the matched hosts with the user's cookies
+    pub credentials: bool,
+}
+
+// Origins accepted by a rule, written as a comma or whitespace separated list
+// such as `http://localhost:3000, https://app.test`. An empty list or `*`
+// accepts any origin, without credentials.
+pub fn grant(allowed: &str, origin: &HeaderValue) -> Option<Grant> {
+    let name = origin.to_str().ok()?;
+    let entries: Vec<&str> = allowed
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|entry| !entry.is_empty())
+        .collect();
+
+    let listed = entries.iter().any(|entry| entry.trim_end_matches('/').eq_ignore_ascii_case(name));
+    let any = entries.is_empty() || entries.contains(&"*");
+    (listed || any).then(|| Grant {
+        origin: origin.clone(),
+        credentials: listed,
+    })
+}
+
+pub fn is_preflight(method: &Method, headers: &HeaderMap) -> bool {
+    method == Method::OPTIONS
+        && headers.contains_key(header::ORIGIN)
+        && headers.contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+// Headers answering a preflight: the requested method and headers are echoed
+// back, so any request the page wants to make is allowed
+pub fn preflight_headers(request: &HeaderMap, grant: &Grant) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    allow_origin(&mut headers, grant);
+
+    if let Some(method) = request.get(header::ACCESS_CONTROL_REQUEST_METHOD) {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, method.clone());
+    }
+    if let Some(requested) = request.get(header::ACCESS_CONTROL_REQUEST_HEADERS) {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, requested.clone());
+    }
+    headers.insert(header::ACCESS_CONTROL_MAX_AGE, HeaderValue::from_static("86400"));
+    headers
+}
+
+// Allow the granted origin on a response, overriding the upstream's own allow-origin headers
+pub fn allow_origin(headers: &mut HeaderMap, grant: &Grant) {
+    // With credentials `*` is taken literally, so expose the response's own header names
+    let exposed = headers
+        .keys()
+        .map(|name| name.as_str())
+        .filter(|name| !name.starts_with("access-control-"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    if let Ok(exposed) = HeaderValue::from_str(&exposed)
+        && !exposed.is_empty()
+    {
+        headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, exposed);
+    }
+
+    // Credentialed requests reject `*`, so the origin is always echoed
+    headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, grant.origin.clone());
+    if grant.credentials {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+    } else {
+        headers.remove(header::ACCESS_CONTROL_ALLOW_CREDENTIALS);
+    }
+
+    let varies_on_origin = headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case("origin"));
+    if !varies_on_origin {
+        headers.append(header::VARY, HeaderValue::from_static("Origin"));
+    }
+}
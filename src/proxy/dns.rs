@@ -0,0 +1,59 @@
+use std::net::IpAddr;
+
+use super::matcher::{strip_brackets, DomainPattern};
+
+// Maps a host to the address the proxy connects to instead of resolving it.
+// Only the connection changes: Host header, SNI and URL stay as requested.
+#[derive(Clone, Debug)]
+pub struct DnsOverride {
+    pub id: String,
+    pub enabled: bool,
+    // Same syntax as proxy rule domains, without regex
+    pub domain: String,
+    pub address: String,
+    // Group the entry belongs to, empty = none; shown in logs
+    pub profile: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct CompiledDnsOverride {
+    domain: DomainPattern,
+    pub address: IpAddr,
+    profile: String,
+}
+
+impl CompiledDnsOverride {
+    pub fn compile(entry: &DnsOverride) -> Result<Self, String> {
+        let domain = DomainPattern::parse(&entry.domain, false)?;
+        Ok(Self {
+            domain,
+            address: parse_address(&entry.address)?,
+            profile: entry.profile.trim().to_string(),
+        })
+    }
+
+    // As shown in logs, e.g. `api.example.com → 10.0.0.5（环境 staging）`
+    pub fn describe(&self, host: &str) -> String {
+        match self.profile.as_str() {
+            "" => format!("{} → {}", host, self.address),
+            profile => format!("{} → {}（环境 {}）", host, self.address, profile),
+        }
+    }
+}
+
+// IPv4 or IPv6 address, brackets optional
+pub fn parse_address(text: &str) -> Result<IpAddr, String> {
+    let text = strip_brackets(text.trim());
+    text.parse()
+        .map_err(|_| format!("地址无效: {}（应为 IP 地址，例如 10.0.0.5 或 ::1）", text))
+}
+
+// The override for `host`; among several matches the most specific domain
+// wins, then the one listed first
+pub fn lookup<'a>(overrides: &'a [CompiledDnsOverride], host: &str) -> Option<&'a CompiledDnsOverride> {
+    let host = strip_brackets(host);
+    overrides
+        .iter()
+        .filter(|entry| entry.domain.matches(host))
+        .min_by_key(|entry| entry.domain.rank())
+}
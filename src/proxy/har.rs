@@ -0,0 +1,443 @@
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use bytes::Bytes;
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use super::capture::{Exchange, Timings};
+use super::server::LogEvent;
+
+// HAR 1.2 (http://www.softwareishard.com/blog/har-12-spec/). Only what we
+// read or write is modelled; unknown fields in imported files are ignored.
+// Proxy-specific details travel in `_`-prefixed custom fields.
+
+#[derive(Serialize, Deserialize)]
+struct Har {
+    log: HarLog,
+}
+
+#[derive(Serialize, Deserialize)]
+struct HarLog {
+    version: String,
+    creator: Creator,
+    #[serde(default)]
+    entries: Vec<Entry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Creator {
+    name: String,
+    version: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Entry {
+    started_date_time: String,
+    #[serde(default)]
+    time: f64,
+    request: Request,
+    response: Response,
+    #[serde(default)]
+    cache: serde_json::Value,
+    #[serde(default)]
+    timings: HarTimings,
+    #[serde(rename = "_protocol", default, skip_serializing_if = "String::is_empty")]
+    protocol: String,
+    #[serde(rename = "_proxyStatus", default, skip_serializing_if = "String::is_empty")]
+    proxy_status: String,
+    #[serde(rename = "_upstreamUrl", default, skip_serializing_if = "String::is_empty")]
+    upstream_url: String,
+    #[serde(rename = "_clientAddress", default, skip_serializing_if = "String::is_empty")]
+    client_address: String,
+    #[serde(rename = "_ruleId", default, skip_serializing_if = "String::is_empty")]
+    rule_id: String,
+    #[serde(rename = "_dnsOverride", default, skip_serializing_if = "String::is_empty")]
+    dns_override: String,
+    #[serde(rename = "_complete", default = "default_true")]
+    complete: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Request {
+    method: String,
+    url: String,
+    #[serde(default)]
+    http_version: String,
+    #[serde(default)]
+    cookies: Vec<serde_json::Value>,
+    #[serde(default)]
+    headers: Vec<NameValue>,
+    #[serde(default)]
+    query_string: Vec<NameValue>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    post_data: Option<PostData>,
+    #[serde(default = "unknown_size")]
+    headers_size: i64,
+    #[serde(default = "unknown_size")]
+    body_size: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Response {
+    status: u16,
+    #[serde(default)]
+    status_text: String,
+    #[serde(default)]
+    http_version: String,
+    #[serde(default)]
+    cookies: Vec<serde_json::Value>,
+    #[serde(default)]
+    headers: Vec<NameValue>,
+    content: Content,
+    #[serde(rename = "redirectURL", default)]
+    redirect_url: String,
+    #[serde(default = "unknown_size")]
+    headers_size: i64,
+    #[serde(default = "unknown_size")]
+    body_size: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct NameValue {
+    name: String,
+    value: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PostData {
+    #[serde(default)]
+    mime_type: String,
+    #[serde(default)]
+    text: String,
+    // Not in the spec, but how binary request bodies are usually written
+    #[serde(rename = "_encoding", default, skip_serializing_if = "Option::is_none")]
+    encoding: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Content {
+    #[serde(default)]
+    size: i64,
+    #[serde(default)]
+    mime_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encoding: Option<String>,
+}
+
+// Milliseconds, -1 where a phase doesn't apply or wasn't measured
+#[derive(Serialize, Deserialize)]
+struct HarTimings {
+    #[serde(default = "not_measured")]
+    blocked: f64,
+    #[serde(default = "not_measured")]
+    dns: f64,
+    #[serde(default = "not_measured")]
+    connect: f64,
+    #[serde(default)]
+    send: f64,
+    #[serde(default)]
+    wait: f64,
+    #[serde(default)]
+    receive: f64,
+    #[serde(default = "not_measured")]
+    ssl: f64,
+}
+
+impl Default for HarTimings {
+    fn default() -> Self {
+        Self {
+            blocked: -1.0,
+            dns: -1.0,
+            connect: -1.0,
+            send: 0.0,
+            wait: 0.0,
+            receive: 0.0,
+            ssl: -1.0,
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn unknown_size() -> i64 {
+    -1
+}
+
+fn not_measured() -> f64 {
+    -1.0
+}
+
+// Serialize the captured events, oldest first. System messages are skipped.
+pub fn export(events: &[LogEvent]) -> Result<String, String> {
+    let har = Har {
+        log: HarLog {
+            version: "1.2".to_string(),
+            creator: Creator {
+                name: "Ovo".to_string(),
+                version: env!("CARGO_PKG_VERSION").to_string(),
+            },
+            entries: events
+                .iter()
+                .filter_map(|event| event.exchange.as_ref().map(|exchange| entry(event, exchange)))
+                .collect(),
+        },
+    };
+    serde_json::to_string_pretty(&har).map_err(|e| format!("生成 HAR 失败: {}", e))
+}
+
+fn entry(event: &LogEvent, exchange: &Exchange) -> Entry {
+    let http_version = if event.protocol.starts_with("HTTP/") {
+        event.protocol.clone()
+    } else {
+        "HTTP/1.1".to_string()
+    };
+    let request_headers = parse_headers(&exchange.request_headers);
+    let response_headers = parse_headers(&exchange.response_headers);
+    let status = u16::try_from(event.status_code).unwrap_or_default();
+
+    let post_data = (exchange.request_size > 0).then(|| {
+        let (text, encoding) = encode_body(&exchange.request_body);
+        PostData {
+            mime_type: header_value(&request_headers, "content-type"),
+            text,
+            encoding,
+        }
+    });
+    let (text, encoding) = encode_body(&exchange.response_body);
+    let content = Content {
+        size: exchange.response_size as i64,
+        mime_type: header_value(&response_headers, "content-type"),
+        text: Some(text),
+        encoding,
+    };
+
+    let timings = &exchange.timings;
+    let total = millis(timings.total);
+    Entry {
+        started_date_time: exchange
+            .started
+            .unwrap_or_else(OffsetDateTime::now_utc)
+            .format(&Rfc3339)
+            .unwrap_or_default(),
+        time: total,
+        request: Request {
+            method: event.method.clone(),
+            url: event.url.clone(),
+            http_version: http_version.clone(),
+            cookies: Vec::new(),
+            query_string: query_string(&event.url),
+            headers: request_headers,
+            post_data,
+            headers_size: -1,
+            body_size: exchange.request_size as i64,
+        },
+        response: Response {
+            status,
+            status_text: StatusCode::from_u16(status)
+                .ok()
+                .and_then(|status| status.canonical_reason())
+                .unwrap_or_default()
+                .to_string(),
+            http_version,
+            cookies: Vec::new(),
+            redirect_url: header_value(&response_headers, "location"),
+            headers: response_headers,
+            content,
+            headers_size: -1,
+            body_size: exchange.response_size as i64,
+        },
+        cache: serde_json::json!({}),
+        timings: HarTimings {
+            blocked: millis(timings.blocked),
+            dns: timings.dns.map_or(-1.0, millis),
+            // HAR counts the TLS handshake as part of connecting
+            connect: match (timings.connect, timings.tls) {
+                (None, None) => -1.0,
+                (connect, tls) => millis(connect.unwrap_or_default() + tls.unwrap_or_default()),
+            },
+            ssl: timings.tls.map_or(-1.0, millis),
+            wait: millis(timings.wait),
+            receive: millis(timings.receive),
+            ..Default::default()
+        },
+        protocol: event.protocol.clone(),
+        proxy_status: event.status.clone(),
+        upstream_url: event.upstream_url.clone(),
+        client_address: exchange.client_addr.clone(),
+        rule_id: exchange.rule_id.clone(),
+        dns_override: exchange.dns_override.clone(),
+        complete: exchange.complete,
+    }
+}
+
+// Read a HAR file, e.g. one saved from browser devtools, into log events
+pub fn import(text: &str) -> Result<Vec<LogEvent>, String> {
+    let har: Har = serde_json::from_str(text).map_err(|e| format!("无法解析 HAR 文件: {}", e))?;
+    har.log.entries.into_iter().map(log_event).collect()
+}
+
+fn log_event(entry: Entry) -> Result<LogEvent, String> {
+    let started = OffsetDateTime::parse(&entry.started_date_time, &Rfc3339)
+        .map_err(|e| format!("无效的 startedDateTime {}: {}", entry.started_date_time, e))?;
+    // The entry's own start time, not the import time `log_time` would give,
+    // in the same format
+    let time = started
+        .to_offset(time::UtcOffset::UTC)
+        .format(time::macros::format_description!("[hour]:[minute]:[second]"))
+        .unwrap_or_default();
+
+    let http_version = entry.request.http_version.to_uppercase();
+    let protocol = match entry.protocol.as_str() {
+        // Browsers write "h2" for HTTP/2
+        "" if matches!(http_version.as_str(), "H2" | "HTTP/2" | "HTTP/2.0") => "HTTP/2".to_string(),
+        "" if entry.request.url.starts_with("https://") => "HTTPS".to_string(),
+        "" if http_version.is_empty() => "HTTP/1.1".to_string(),
+        "" => http_version,
+        protocol => protocol.to_string(),
+    };
+    let status = match entry.proxy_status.as_str() {
+        "" => format!("{} {}", entry.response.status, entry.response.status_text).trim().to_string(),
+        status => status.to_string(),
+    };
+
+    let request_body = match &entry.request.post_data {
+        Some(post_data) => decode_body(&post_data.text, post_data.encoding.as_deref())?,
+        None => Bytes::new(),
+    };
+    let response_body = match &entry.response.content.text {
+        Some(text) => decode_body(text, entry.response.content.encoding.as_deref())?,
+        None => Bytes::new(),
+    };
+    let request_size = u64::try_from(entry.request.body_size).unwrap_or(request_body.len() as u64);
+    let response_size = u64::try_from(entry.response.content.size)
+        .ok()
+        .filter(|size| *size > 0)
+        .unwrap_or(response_body.len() as u64);
+
+    // Some exporters write -1 for phases they didn't measure
+    let measured = |ms: f64| (ms >= 0.0).then(|| Duration::from_secs_f64(ms / 1000.0));
+    let har = &entry.timings;
+    let tls = measured(har.ssl);
+    let timings = Timings {
+        blocked: measured(har.blocked).unwrap_or_default(),
+        dns: measured(har.dns),
+        connect: measured(har.connect).map(|connect| connect.saturating_sub(tls.unwrap_or_default())),
+        tls,
+        // Sending isn't timed separately here
+        wait: measured(har.send).unwrap_or_default() + measured(har.wait).unwrap_or_default(),
+        receive: measured(har.receive).unwrap_or_default(),
+        total: measured(entry.time).unwrap_or_default(),
+        reused: false,
+    };
+
+    Ok(LogEvent {
+        time,
+        method: entry.request.method,
+        protocol,
+        url: entry.request.url,
+        status,
+        status_code: entry.response.status as i32,
+        upstream_url: entry.upstream_url,
+        exchange: Some(Exchange {
+            client_addr: entry.client_address,
+            rule_id: entry.rule_id,
+            dns_override: entry.dns_override,
+            request_headers: format_headers(&entry.request.headers),
+            request_body,
+            request_size,
+            response_headers: format_headers(&entry.response.headers),
+            response_body,
+            response_size,
+            complete: entry.complete,
+            started: Some(started),
+            timings,
+            // Messages aren't part of the HAR, so there's nothing to show
+            websocket: None,
+        }),
+    })
+}
+
+// Text bodies are written as is, anything else base64 encoded
+fn encode_body(body: &[u8]) -> (String, Option<String>) {
+    match std::str::from_utf8(body) {
+        Ok(text) => (text.to_string(), None),
+        Err(_) => (BASE64.encode(body), Some("base64".to_string())),
+    }
+}
+
+fn decode_body(text: &str, encoding: Option<&str>) -> Result<Bytes, String> {
+    match encoding {
+        Some(encoding) if encoding.eq_ignore_ascii_case("base64") => BASE64
+            .decode(text.trim())
+            .map(Bytes::from)
+            .map_err(|e| format!("无法解码 base64 内容: {}", e)),
+        _ => Ok(Bytes::from(text.to_string())),
+    }
+}
+
+// The captured `Name: value` lines as HAR name/value pairs
+fn parse_headers(text: &str) -> Vec<NameValue> {
+    text.lines()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| NameValue {
+            name: name.trim().to_string(),
+            value: value.trim().to_string(),
+        })
+        .collect()
+}
+
+fn format_headers(headers: &[NameValue]) -> String {
+    headers
+        .iter()
+        // HTTP/2 pseudo headers as written by browsers
+        .filter(|header| !header.name.starts_with(':'))
+        .map(|header| format!("{}: {}", header.name, header.value))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn header_value(headers: &[NameValue], name: &str) -> String {
+    headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case(name))
+        .map(|header| header.value.clone())
+        .unwrap_or_default()
+}
+
+// Query parameters as they appear in the URL, without decoding
+fn query_string(url: &str) -> Vec<NameValue> {
+    let Some((_, query)) = url.split_once('?') else {
+        return Vec::new();
+    };
+    query
+        .split('#')
+        .next()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            NameValue {
+                name: name.to_string(),
+                value: value.to_string(),
+            }
+        })
+        .collect()
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
@@ -0,0 +1,101 @@
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+
+use super::matcher::{DomainPattern, PathPattern};
+
+#[derive(Clone, Debug)]
+pub struct HeaderRewrite {
+    pub id: String,
+    pub enabled: bool,
+    // Scope: domain pattern (same syntax as proxy rules) and path prefix, empty = any
+    pub domain: String,
+    pub path: String,
+    // "request" rewrites before forwarding upstream, "response" before replying
+    pub phase: String,
+    // "set" replaces every value, "add" appends one, "remove" drops the header
+    pub action: String,
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    Request,
+    Response,
+}
+
+#[derive(Clone, Debug)]
+enum Action {
+    Set(HeaderValue),
+    Add(HeaderValue),
+    Remove,
+}
+
+#[derive(Clone, Debug)]
+pub struct CompiledHeaderRewrite {
+    domain: Option<DomainPattern>,
+    path: PathPattern,
+    phase: Phase,
+    name: HeaderName,
+    action: Action,
+}
+
+impl CompiledHeaderRewrite {
+    pub fn compile(rewrite: &HeaderRewrite) -> Result<Self, String> {
+        let domain = if rewrite.domain.trim().is_empty() {
+            None
+        } else {
+            Some(DomainPattern::parse(&rewrite.domain, false)?)
+        };
+        let path = PathPattern::parse(&rewrite.path, "prefix")?;
+
+        let phase = match rewrite.phase.as_str() {
+            "request" => Phase::Request,
+            "response" => Phase::Response,
+            other => return Err(format!("未知的阶段: {}", other)),
+        };
+
+        let name = HeaderName::from_bytes(rewrite.name.trim().as_bytes())
+            .map_err(|_| format!("无效的 Header 名称: {}", rewrite.name))?;
+
+        let value = || {
+            HeaderValue::from_str(&rewrite.value).map_err(|_| format!("无效的 Header 值: {}", rewrite.value))
+        };
+        let action = match rewrite.action.as_str() {
+            "set" => Action::Set(value()?),
+            "add" => Action::Add(value()?),
+            "remove" => Action::Remove,
+            other => return Err(format!("未知的操作: {}", other)),
+        };
+
+        Ok(Self {
+            domain,
+            path,
+            phase,
+            name,
+            action,
+        })
+    }
+
+    fn applies(&self, phase: Phase, host: &str, path: &str) -> bool {
+        self.phase == phase
+            && self.domain.as_ref().is_none_or(|domain| domain.matches(host))
+            && self.path.matches(path)
+    }
+}
+
+// Apply the rewrites for `phase` that cover `host` and `path`, in list order
+pub fn apply(rewrites: &[CompiledHeaderRewrite], phase: Phase, host: &str, path: &str, headers: &mut HeaderMap) {
+    for rewrite in rewrites.iter().filter(|rewrite| rewrite.applies(phase, host, path)) {
+        match &rewrite.action {
+            Action::Set(value) => {
+                headers.insert(rewrite.name.clone(), value.clone());
+            }
+            Action::Add(value) => {
+                headers.append(rewrite.name.clone(), value.clone());
+            }
+            Action::Remove => {
+                headers.remove(&rewrite.name);
+            }
+        }
+    }
+}
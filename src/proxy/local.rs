@@ -0,0 +1,82 @@
+use std::path::Path;
+
+use bytes::Bytes;
+use hyper::header::{self, HeaderMap, HeaderName, HeaderValue};
+use hyper::StatusCode;
+
+use super::server::Rule;
+
+// A response served by a map-local rule instead of the upstream
+pub struct LocalResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+// Build the response for `rule`. The file is read on every request so edits
+// show up without touching the rule.
+pub async fn load(rule: &Rule) -> Result<LocalResponse, String> {
+    let status = StatusCode::from_u16(rule.local_status).map_err(|_| format!("无效的状态码: {}", rule.local_status))?;
+    let mut headers = parse_headers(&rule.local_headers)?;
+
+    let file = rule.local_file.trim();
+    let body = if file.is_empty() {
+        Bytes::from(rule.local_body.clone())
+    } else {
+        tokio::fs::read(file)
+            .await
+            .map(Bytes::from)
+            .map_err(|e| format!("读取本地文件 {} 失败: {}", file, e))?
+    };
+
+    let content_type = match rule.local_content_type.trim() {
+        "" if !file.is_empty() => guess_content_type(Path::new(file)),
+        "" => "text/plain; charset=utf-8",
+        content_type => content_type,
+    };
+    if !headers.contains_key(header::CONTENT_TYPE) {
+        let content_type = HeaderValue::from_str(content_type).map_err(|_| format!("无效的 Content-Type: {}", content_type))?;
+        headers.insert(header::CONTENT_TYPE, content_type);
+    }
+
+    Ok(LocalResponse { status, headers, body })
+}
+
+// One `Name: value` header per line, blank lines ignored
+pub fn parse_headers(text: &str) -> Result<HeaderMap, String> {
+    let mut headers = HeaderMap::new();
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| format!("Header 需写成 名称: 值 的形式: {}", line))?;
+        let name = HeaderName::from_bytes(name.trim().as_bytes()).map_err(|_| format!("无效的 Header 名称: {}", name.trim()))?;
+        let value = HeaderValue::from_str(value.trim()).map_err(|_| format!("无效的 Header 值: {}", value.trim()))?;
+        headers.append(name, value);
+    }
+    Ok(headers)
+}
+
+fn guess_content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase())
+        .unwrap_or_default();
+
+    match extension.as_str() {
+        "json" => "application/json",
+        "html" | "htm" => "text/html; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "wasm" => "application/wasm",
+        "txt" | "log" => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
@@ -0,0 +1,292 @@
+use std::net::IpAddr;
+
+use regex::{Regex, RegexBuilder};
+
+// Domain patterns accepted in `Rule.domain`:
+//
+// - `api.local`         exact host (case-insensitive)
+// - `*.example.test`    `*` stands for exactly one label: `a.example.test`, not `a.b.example.test`
+// - `**.corp.local`     `**` stands for one or more labels: `a.corp.local`, `a.b.corp.local`
+// - regex mode          the whole host must match the expression, e.g. `tenant-\d+\.local`
+//
+// When several enabled rules match the same host, the winner is chosen by:
+//
+// 1. exact beats wildcard beats regex
+// 2. among wildcards, more literal labels win (`*.a.example.test` over `**.example.test`),
+//    then `*`-only patterns over ones containing `**`
+// 3. otherwise the rule listed first wins
+#[derive(Clone, Debug)]
+pub enum DomainPattern {
+    Exact(String),
+    Wildcard(Vec<String>),
+    Regex(Regex),
+}
+
+impl DomainPattern {
+    pub fn parse(domain: &str, regex: bool) -> Result<Self, String> {
+        let domain = domain.trim();
+        if domain.is_empty() {
+            return Err("域名不能为空".to_string());
+        }
+
+        if regex {
+            return RegexBuilder::new(&format!("^(?:{})$", domain))
+                .case_insensitive(true)
+                .build()
+                .map(DomainPattern::Regex)
+                .map_err(|e| format!("正则表达式无效: {}", e));
+        }
+
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        let labels: Vec<String> = domain.split('.').map(|label| label.to_string()).collect();
+        for label in &labels {
+            if label.is_empty() {
+                return Err("域名中存在空的标签".to_string());
+            }
+            if label.contains('*') && label != "*" && label != "**" {
+                return Err("通配符 * / ** 必须单独占据一级域名".to_string());
+            }
+            if !label.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '*')) {
+                return Err(format!("域名包含无效字符: {}", label));
+            }
+        }
+
+        if labels.iter().any(|label| label.contains('*')) {
+            Ok(DomainPattern::Wildcard(labels))
+        } else {
+            Ok(DomainPattern::Exact(domain))
+        }
+    }
+
+    pub fn matches(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.');
+        match self {
+            DomainPattern::Exact(domain) => domain.eq_ignore_ascii_case(host),
+            DomainPattern::Wildcard(labels) => {
+                let host = host.to_ascii_lowercase();
+                let host_labels: Vec<&str> = host.split('.').collect();
+                match_labels(labels, &host_labels)
+            }
+            DomainPattern::Regex(regex) => regex.is_match(host),
+        }
+    }
+
+    // Lower ranks win; see the precedence rules above
+    pub fn rank(&self) -> (u8, usize, bool) {
+        match self {
+            DomainPattern::Exact(_) => (0, 0, false),
+            DomainPattern::Wildcard(labels) => {
+                let literal = labels.iter().filter(|label| !label.contains('*')).count();
+                (1, usize::MAX - literal, labels.iter().any(|label| label == "**"))
+            }
+            DomainPattern::Regex(_) => (2, 0, false),
+        }
+    }
+
+    // The whole-host regular expression equivalent to the pattern, for the
+    // PAC script; meant to be used case-insensitively
+    pub fn regex_source(&self) -> String {
+        match self {
+            DomainPattern::Exact(domain) => format!("^{}$", regex::escape(domain)),
+            DomainPattern::Wildcard(labels) => {
+                let labels: Vec<String> = labels
+                    .iter()
+                    .map(|label| match label.as_str() {
+                        "*" => "[^.]+".to_string(),
+                        "**" => "[^.]+(?:\\.[^.]+)*".to_string(),
+                        label => regex::escape(label),
+                    })
+                    .collect();
+                format!("^{}$", labels.join("\\."))
+            }
+            DomainPattern::Regex(regex) => regex.as_str().to_string(),
+        }
+    }
+}
+
+fn match_labels(pattern: &[String], host: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => host.is_empty(),
+        Some((label, rest)) if label == "**" => {
+            (1..=host.len()).any(|taken| match_labels(rest, &host[taken..]))
+        }
+        Some((label, rest)) => match host.split_first() {
+            Some((first, host_rest)) => (label == "*" || label == first) && match_labels(rest, host_rest),
+            None => false,
+        },
+    }
+}
+
+// Path matchers accepted in `Rule.path`, tested against the request path
+// without its query string:
+//
+// - empty               any path
+// - `prefix` mode       `/api` (or `/api/*`) matches `/api` and `/api/...`, but not `/apix`
+// - `exact` mode        the path must be identical
+// - `regex` mode        the whole path must match the expression
+//
+// Among rules whose domains rank equally, exact paths win over prefixes (longest
+// first), then regexes, then rules without a path.
+#[derive(Clone, Debug)]
+pub enum PathPattern {
+    Any,
+    Exact(String),
+    Prefix(String),
+    Regex(Regex),
+}
+
+impl PathPattern {
+    pub fn parse(path: &str, mode: &str) -> Result<Self, String> {
+        let path = path.trim();
+        if path.is_empty() {
+            return Ok(PathPattern::Any);
+        }
+
+        match mode {
+            "regex" => Regex::new(&format!("^(?:{})$", path))
+                .map(PathPattern::Regex)
+                .map_err(|e| format!("路径正则表达式无效: {}", e)),
+            "exact" | "prefix" if !path.starts_with('/') => Err("路径必须以 / 开头".to_string()),
+            "exact" => Ok(PathPattern::Exact(path.to_string())),
+            "prefix" => {
+                let prefix = path.trim_end_matches('*').trim_end_matches('/');
+                Ok(PathPattern::Prefix(prefix.to_string()))
+            }
+            _ => Err(format!("未知的路径匹配方式: {}", mode)),
+        }
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        match self {
+            PathPattern::Any => true,
+            PathPattern::Exact(exact) => path == exact,
+            PathPattern::Prefix(prefix) => match path.strip_prefix(prefix.as_str()) {
+                Some(rest) => rest.is_empty() || rest.starts_with('/') || prefix.is_empty(),
+                None => false,
+            },
+            PathPattern::Regex(regex) => regex.is_match(path),
+        }
+    }
+
+    // Replace the matched part of `path`: the prefix for prefix rules, the whole
+    // path otherwise (regex replacements may use `$1` style captures)
+    pub fn rewrite(&self, path: &str, replacement: &str) -> String {
+        let rewritten = match self {
+            PathPattern::Any => path.to_string(),
+            PathPattern::Exact(_) => replacement.to_string(),
+            PathPattern::Prefix(prefix) => {
+                let rest = path.strip_prefix(prefix.as_str()).unwrap_or(path);
+                format!("{}{}", replacement.trim_end_matches('/'), rest)
+            }
+            PathPattern::Regex(regex) => regex.replace(path, replacement).into_owned(),
+        };
+
+        if rewritten.starts_with('/') {
+            rewritten
+        } else {
+            format!("/{}", rewritten)
+        }
+    }
+
+    // Lower ranks win; see the precedence rules above
+    pub fn rank(&self) -> (u8, usize) {
+        match self {
+            PathPattern::Exact(_) => (0, 0),
+            PathPattern::Prefix(prefix) => (1, usize::MAX - prefix.len()),
+            PathPattern::Regex(_) => (2, 0),
+            PathPattern::Any => (3, 0),
+        }
+    }
+}
+
+// An address block such as `10.0.0.0/8` or `fd00::/8`; a bare address is a
+// block of one. IPv4-mapped IPv6 addresses match their IPv4 form.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let text = text.trim();
+        let (addr, prefix) = match text.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (text, None),
+        };
+        let network: IpAddr = strip_brackets(addr).parse().map_err(|_| format!("IP 地址无效: {}", addr))?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| format!("网络前缀无效: {}", text))?,
+            None => max,
+        };
+        Ok(Self { network, prefix })
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let addr = match addr {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(addr),
+            addr => addr,
+        };
+        match (self.network, addr) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+
+    // PAC (JavaScript) condition for a `host` inside the block. PAC scripts
+    // only compare IPv4 addresses, so IPv6 blocks have none.
+    pub fn pac_condition(&self) -> Option<String> {
+        let IpAddr::V4(network) = self.network else {
+            return None;
+        };
+        let mask = std::net::Ipv4Addr::from(u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0));
+        // isInNet resolves host names, so only literal addresses are tested
+        Some(format!(
+            "(/^\\d+\\.\\d+\\.\\d+\\.\\d+$/.test(host) && isInNet(host, \"{}\", \"{}\"))",
+            network, mask
+        ))
+    }
+}
+
+// Client addresses allowed to connect, as a list of `Cidr` blocks separated
+// by commas, semicolons or newlines. The local machine is always allowed; an
+// empty list allows no one else, so binding to all interfaces doesn't open
+// the proxy to the whole network.
+#[derive(Clone, Debug, Default)]
+pub struct Allowlist(Vec<Cidr>);
+
+impl Allowlist {
+    pub fn parse(list: &str) -> Result<Self, String> {
+        list.split([',', ';', '\n'])
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(Cidr::parse)
+            .collect::<Result<_, _>>()
+            .map(Allowlist)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn allows(&self, addr: IpAddr) -> bool {
+        addr.to_canonical().is_loopback() || self.0.iter().any(|cidr| cidr.contains(addr))
+    }
+}
+
+// Host or address without the brackets around IPv6 literals
+pub fn strip_brackets(host: &str) -> &str {
+    host.trim_start_matches('[').trim_end_matches(']')
+}
@@ -1,2 +1,21 @@
 pub mod server;
 pub mod ca;
+pub mod capture;
+pub mod matcher;
+pub mod headers;
+pub mod cors;
+pub mod local;
+pub mod remote;
+pub mod chain;
+pub mod socks;
+pub mod breakpoint;
+pub mod har;
+pub mod pool;
+pub mod throttle;
+pub mod tls;
+pub mod websocket;
+pub mod balance;
+pub mod auth;
+pub mod dns;
+pub mod nameserver;
+pub mod pac;
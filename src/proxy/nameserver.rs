@@ -0,0 +1,220 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+
+use super::matcher::strip_brackets;
+
+// Just enough of DNS (RFC 1035) for the built-in name server: reading a
+// single-question query, answering it with addresses or an error code, and
+// passing it on to another server unchanged.
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+pub const RCODE_FORMERR: u8 = 1;
+pub const RCODE_SERVFAIL: u8 = 2;
+pub const RCODE_REFUSED: u8 = 5;
+
+// Answers point at the proxy, which may move, so they aren't cached for long
+const ANSWER_TTL: u32 = 60;
+
+// How long to wait for the upstream server
+pub const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(3);
+
+// Large enough for EDNS clients; classic UDP messages are at most 512 bytes
+pub const MAX_UDP_MESSAGE: usize = 4096;
+
+const HEADER_LEN: usize = 12;
+
+#[derive(Clone, Debug)]
+pub struct Query {
+    pub id: u16,
+    // Lowercase, without the trailing dot
+    pub name: String,
+    pub qtype: u16,
+    qclass: u16,
+    recursion_desired: bool,
+    // The question as it appeared in the query, echoed in replies
+    question: Vec<u8>,
+}
+
+impl Query {
+    // "A", "AAAA" or e.g. "TYPE65", for the log
+    pub fn type_name(&self) -> String {
+        match self.qtype {
+            TYPE_A => "A".to_string(),
+            TYPE_AAAA => "AAAA".to_string(),
+            other => format!("TYPE{}", other),
+        }
+    }
+
+    // Whether `addr` answers this query: A queries take IPv4 addresses and
+    // AAAA queries IPv6 ones
+    pub fn wants(&self, addr: IpAddr) -> bool {
+        self.qclass == CLASS_IN
+            && matches!((self.qtype, addr), (TYPE_A, IpAddr::V4(_)) | (TYPE_AAAA, IpAddr::V6(_)))
+    }
+}
+
+// A standard query with exactly one question; None for anything else
+pub fn parse_query(msg: &[u8]) -> Option<Query> {
+    let header = msg.get(..HEADER_LEN)?;
+    let flags = u16::from_be_bytes([header[2], header[3]]);
+    let is_response = flags & 0x8000 != 0;
+    let opcode = (flags >> 11) & 0xf;
+    if is_response || opcode != 0 || u16::from_be_bytes([header[4], header[5]]) != 1 {
+        return None;
+    }
+
+    let mut labels = Vec::new();
+    let mut pos = HEADER_LEN;
+    loop {
+        let len = *msg.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        // Queries carry the name in full, so compression pointers aren't expected
+        if len > 63 {
+            return None;
+        }
+        let label = msg.get(pos..pos + len)?;
+        labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+        pos += len;
+    }
+    let fields = msg.get(pos..pos + 4)?;
+    Some(Query {
+        id: u16::from_be_bytes([header[0], header[1]]),
+        name: labels.join("."),
+        qtype: u16::from_be_bytes([fields[0], fields[1]]),
+        qclass: u16::from_be_bytes([fields[2], fields[3]]),
+        recursion_desired: flags & 0x0100 != 0,
+        question: msg[HEADER_LEN..pos + 4].to_vec(),
+    })
+}
+
+// Authoritative answer with `addrs`; empty means the name exists but has no
+// records of the asked type
+pub fn answer(query: &Query, addrs: &[IpAddr]) -> Vec<u8> {
+    let mut msg = header(query.id, query.recursion_desired, true, 0, 1, addrs.len() as u16);
+    msg.extend_from_slice(&query.question);
+    for addr in addrs {
+        // The owner name points back at the question
+        msg.extend_from_slice(&[0xc0, HEADER_LEN as u8]);
+        let (rtype, data) = match addr {
+            IpAddr::V4(addr) => (TYPE_A, addr.octets().to_vec()),
+            IpAddr::V6(addr) => (TYPE_AAAA, addr.octets().to_vec()),
+        };
+        msg.extend_from_slice(&rtype.to_be_bytes());
+        msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+        msg.extend_from_slice(&ANSWER_TTL.to_be_bytes());
+        msg.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        msg.extend_from_slice(&data);
+    }
+    msg
+}
+
+// Reply to `query` with an error code and no answers
+pub fn error(query: &Query, rcode: u8) -> Vec<u8> {
+    let mut msg = header(query.id, query.recursion_desired, false, rcode, 1, 0);
+    msg.extend_from_slice(&query.question);
+    msg
+}
+
+// FORMERR for a message that isn't a query we can read, as long as it has an
+// id to reply to; responses are never answered
+pub fn format_error(msg: &[u8]) -> Option<Vec<u8>> {
+    let header_bytes = msg.get(..HEADER_LEN)?;
+    if header_bytes[2] & 0x80 != 0 {
+        return None;
+    }
+    let id = u16::from_be_bytes([header_bytes[0], header_bytes[1]]);
+    Some(header(id, header_bytes[2] & 0x01 != 0, false, RCODE_FORMERR, 0, 0))
+}
+
+fn header(id: u16, recursion_desired: bool, authoritative: bool, rcode: u8, questions: u16, answers: u16) -> Vec<u8> {
+    let mut flags: u16 = 0x8000 | rcode as u16;
+    if authoritative {
+        flags |= 0x0400;
+    }
+    if recursion_desired {
+        flags |= 0x0100;
+    }
+    let mut msg = Vec::with_capacity(512);
+    msg.extend_from_slice(&id.to_be_bytes());
+    msg.extend_from_slice(&flags.to_be_bytes());
+    msg.extend_from_slice(&questions.to_be_bytes());
+    msg.extend_from_slice(&answers.to_be_bytes());
+    msg.extend_from_slice(&[0, 0, 0, 0]);
+    msg
+}
+
+// Send `msg` to `server` as is and return its reply, over TCP when the
+// client asked over TCP
+pub async fn forward(server: SocketAddr, msg: &[u8], tcp: bool) -> io::Result<Vec<u8>> {
+    let exchange = async {
+        if tcp {
+            let mut stream = TcpStream::connect(server).await?;
+            write_tcp_message(&mut stream, msg).await?;
+            return read_tcp_message(&mut stream).await;
+        }
+
+        let local: IpAddr = if server.is_ipv4() { Ipv4Addr::UNSPECIFIED.into() } else { Ipv6Addr::UNSPECIFIED.into() };
+        let socket = UdpSocket::bind((local, 0)).await?;
+        socket.connect(server).await?;
+        socket.send(msg).await?;
+        let mut buf = vec![0u8; MAX_UDP_MESSAGE];
+        loop {
+            let len = socket.recv(&mut buf).await?;
+            // Stray datagrams with another id aren't the reply
+            if len >= 2 && buf[..2] == msg[..2] {
+                buf.truncate(len);
+                return Ok(buf);
+            }
+        }
+    };
+    tokio::time::timeout(UPSTREAM_TIMEOUT, exchange)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "DNS server did not answer"))?
+}
+
+// DNS over TCP prefixes each message with its length
+pub async fn read_tcp_message<S>(stream: &mut S) -> io::Result<Vec<u8>>
+where
+    S: AsyncRead + Unpin,
+{
+    let len = stream.read_u16().await? as usize;
+    let mut msg = vec![0u8; len];
+    stream.read_exact(&mut msg).await?;
+    Ok(msg)
+}
+
+pub async fn write_tcp_message<S>(stream: &mut S, msg: &[u8]) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let len = u16::try_from(msg.len()).map_err(|_| io::Error::other("DNS message too long"))?;
+    let mut framed = Vec::with_capacity(msg.len() + 2);
+    framed.extend_from_slice(&len.to_be_bytes());
+    framed.extend_from_slice(msg);
+    stream.write_all(&framed).await
+}
+
+// Where to forward other queries, e.g. `1.1.1.1` or `[2606:4700::1111]:53`
+pub fn parse_upstream(text: &str) -> Result<Option<SocketAddr>, String> {
+    let text = text.trim();
+    if text.is_empty() {
+        return Ok(None);
+    }
+    if let Ok(addr) = text.parse::<SocketAddr>() {
+        return Ok(Some(addr));
+    }
+    strip_brackets(text)
+        .parse::<IpAddr>()
+        .map(|ip| Some(SocketAddr::new(ip, 53)))
+        .map_err(|_| format!("上游 DNS 无效: {}（应为 IP 地址，可带端口，例如 1.1.1.1 或 192.168.1.1:53）", text))
+}
@@ -0,0 +1,137 @@
+use super::chain::Bypass;
+use super::matcher::DomainPattern;
+
+// Proxy auto-config (PAC) script built from the rules: hosts matching an
+// enabled rule go through Ovo, everything else connects directly. Browsers
+// fetch it from `PAC_PATH` on the HTTP listener.
+
+pub const PAC_PATH: &str = "/proxy.pac";
+pub const CONTENT_TYPE: &str = "application/x-ns-proxy-autoconfig";
+
+// `proxy` is the listener as the browser reached it, e.g. `192.168.1.5:8080`.
+// Hosts in `bypass` stay direct even when a rule matches them. Rules are
+// given by id, which names the ones left out in the script and the console.
+pub fn script<'a>(rules: impl IntoIterator<Item = (&'a str, &'a DomainPattern)>, bypass: &Bypass, proxy: &str) -> String {
+    let mut sources: Vec<String> = Vec::new();
+    let mut left_out = Vec::new();
+    for (id, domain) in rules {
+        match js_regex(&domain.regex_source()) {
+            Ok(source) if !sources.contains(&source) => sources.push(source),
+            Ok(_) => {}
+            Err(e) => {
+                println!("Rule {} left out of the PAC script: {}", id, e);
+                left_out.push(format!("// Rule {} left out: {}\n", id, e));
+            }
+        }
+    }
+
+    let mut script = String::from("// Generated by Ovo from its enabled proxy rules\n\n");
+    if !left_out.is_empty() {
+        script.push_str(&left_out.concat());
+        script.push('\n');
+    }
+    script.push_str(&format!("var PROXY = {};\n\n", js_string(&format!("PROXY {}", proxy))));
+    // Older engines may still reject a translated regex; only its rule is
+    // skipped then, rather than the whole script breaking
+    script.push_str("function pattern(source) {\n  try {\n    return new RegExp(source, \"i\");\n  } catch (e) {\n    return null;\n  }\n}\n\n");
+    script.push_str("var RULES = [\n");
+    for source in &sources {
+        script.push_str(&format!("  pattern({}),\n", js_string(source)));
+    }
+    script.push_str("];\n\n");
+
+    script.push_str("function FindProxyForURL(url, host) {\n");
+    script.push_str("  host = host.toLowerCase();\n");
+    let bypass = bypass.pac_conditions();
+    if !bypass.is_empty() {
+        script.push_str(&format!("  if ({}) {{\n    return \"DIRECT\";\n  }}\n", bypass.join(" ||\n      ")));
+    }
+    script.push_str("  for (var i = 0; i < RULES.length; i++) {\n");
+    script.push_str("    if (RULES[i] && RULES[i].test(host)) {\n      return PROXY;\n    }\n  }\n");
+    script.push_str("  return \"DIRECT\";\n}\n");
+    script
+}
+
+// A rule regex rewritten from Rust's syntax into JavaScript's, for a RegExp
+// with the "i" flag. Rust syntax without a JavaScript equivalent is an error.
+fn js_regex(source: &str) -> Result<String, String> {
+    let mut js = String::new();
+    let mut chars = source.chars().peekable();
+    let mut in_class = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                let escaped = chars.next().ok_or("trailing backslash")?;
+                match escaped {
+                    'A' => js.push('^'),
+                    'z' => js.push('$'),
+                    'x' if chars.peek() == Some(&'{') => {
+                        chars.next();
+                        let hex: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                        let code = u32::from_str_radix(&hex, 16).map_err(|_| format!("invalid escape \\x{{{}}}", hex))?;
+                        if code > 0xFFFF {
+                            return Err(format!("\\x{{{}}} is outside the Basic Multilingual Plane", hex));
+                        }
+                        js.push_str(&format!("\\u{:04X}", code));
+                    }
+                    'b' | 'B' if chars.peek() == Some(&'{') => return Err("\\b{...} word boundaries".to_string()),
+                    'p' | 'P' => return Err(format!("Unicode classes \\{}", escaped)),
+                    '<' | '>' => return Err(format!("word boundary \\{}", escaped)),
+                    _ => {
+                        js.push('\\');
+                        js.push(escaped);
+                    }
+                }
+            }
+            '[' if in_class => {
+                return Err(if chars.peek() == Some(&':') { "POSIX classes [[:...:]]" } else { "nested classes" }.to_string());
+            }
+            '[' => {
+                in_class = true;
+                js.push('[');
+                if chars.peek() == Some(&'^') {
+                    js.push(chars.next().unwrap_or('^'));
+                }
+                // A leading `]` is literal in Rust but would close the class in JavaScript
+                if chars.peek() == Some(&']') {
+                    chars.next();
+                    js.push_str("\\]");
+                }
+            }
+            ']' if in_class => {
+                in_class = false;
+                js.push(']');
+            }
+            '&' | '-' | '~' if in_class && chars.peek() == Some(&c) => {
+                return Err(format!("class set operation {}{}", c, c));
+            }
+            '(' if !in_class && chars.peek() == Some(&'?') => {
+                chars.next();
+                let mut group = String::new();
+                while let Some(&next) = chars.peek() {
+                    if next == ':' || next == ')' || next == '>' {
+                        break;
+                    }
+                    group.push(next);
+                    chars.next();
+                }
+                let end = chars.next().ok_or("unclosed group")?;
+                match (group.as_str(), end) {
+                    // Names only label captures, which the script doesn't use
+                    (name, '>') if name.starts_with('<') || name.starts_with("P<") => js.push_str("(?:"),
+                    // Matching is case-insensitive anyway
+                    ("" | "i", ':') => js.push_str("(?:"),
+                    ("i", ')') => {}
+                    _ => return Err(format!("inline flags (?{}{}", group, end)),
+                }
+            }
+            _ => js.push(c),
+        }
+    }
+    Ok(js)
+}
+
+// JSON strings are valid JavaScript string literals
+pub fn js_string(text: &str) -> String {
+    serde_json::to_string(text).unwrap_or_default()
+}
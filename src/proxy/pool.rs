@@ -0,0 +1,286 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use hyper::client::conn::{http1, http2, TrySendError};
+use hyper::{Request, Response};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use super::capture::ConnectTimings;
+use super::chain::UpstreamProxy;
+
+// Idle connections are closed after this long
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+// HTTP/1 connections in use per upstream; further requests wait for one to
+// become free. HTTP/2 connections are shared and don't count.
+pub const MAX_CONNECTIONS_PER_HOST: usize = 32;
+
+// How often idle connections are checked for expiry
+const SWEEP_INTERVAL: Duration = Duration::from_secs(15);
+
+type Body = BoxBody<Bytes, hyper::Error>;
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+// A handshaken connection to the upstream
+pub enum UpstreamSender {
+    Http1(http1::SendRequest<Body>),
+    Http2(http2::SendRequest<Body>),
+}
+
+impl UpstreamSender {
+    pub fn is_http2(&self) -> bool {
+        matches!(self, UpstreamSender::Http2(_))
+    }
+
+    // On failure the request is handed back if it never reached the connection
+    pub async fn try_send_request(
+        &mut self,
+        req: Request<Body>,
+    ) -> Result<Response<hyper::body::Incoming>, TrySendError<Request<Body>>> {
+        match self {
+            UpstreamSender::Http1(sender) => sender.try_send_request(req).await,
+            UpstreamSender::Http2(sender) => sender.try_send_request(req).await,
+        }
+    }
+}
+
+// Connections are only reused for requests that would open an identical one
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PoolKey {
+    pub addr: String,
+    // TLS server name and whether the certificate is verified
+    pub tls: Option<(String, bool)>,
+    pub h2c: bool,
+    // False for requests that need an HTTP/1 upgrade
+    pub allow_h2: bool,
+    pub via: Option<UpstreamProxy>,
+    // Address from a DNS override, connected to instead of resolving `addr`
+    pub resolved: Option<IpAddr>,
+}
+
+struct Host {
+    limit: Arc<Semaphore>,
+    idle: Vec<(http1::SendRequest<Body>, Instant)>,
+    // HTTP/2 multiplexes every request over one connection, and when it was last used
+    shared: Option<(http2::SendRequest<Body>, Instant)>,
+}
+
+impl Host {
+    // Whether connections hold slots or requests wait for one; both keep a
+    // reference to the semaphore, so dropping the host would reset the limit
+    fn in_use(&self) -> bool {
+        Arc::strong_count(&self.limit) > 1
+    }
+}
+
+impl Default for Host {
+    fn default() -> Self {
+        Self {
+            limit: Arc::new(Semaphore::new(MAX_CONNECTIONS_PER_HOST)),
+            idle: Vec::new(),
+            shared: None,
+        }
+    }
+}
+
+// A connection handed out by the pool. HTTP/1 connections hold one of their
+// host's slots until they are released.
+pub struct Pooled {
+    pub sender: UpstreamSender,
+    // Whether the connection was used before, in which case the upstream may
+    // have closed it in the meantime
+    pub reused: bool,
+    // How long opening the connection took; zero for reused ones
+    pub timings: ConnectTimings,
+    key: PoolKey,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+// Keep-alive connections to upstreams, shared by all clients
+#[derive(Default)]
+pub struct Pool {
+    hosts: Mutex<HashMap<PoolKey, Host>>,
+    sweeping: AtomicBool,
+}
+
+impl Pool {
+    // A live connection for `key`, or a new one from `connect` when there is
+    // none idle. Waits while the host is at its connection limit.
+    pub async fn get<F, Fut>(&self, key: PoolKey, connect: F) -> Result<Pooled, BoxError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<(UpstreamSender, ConnectTimings), BoxError>>,
+    {
+        let limit = {
+            let Ok(mut hosts) = self.hosts.lock() else {
+                return Err("connection pool is poisoned".into());
+            };
+            let host = hosts.entry(key.clone()).or_default();
+            if let Some((sender, last_used)) = host.shared.as_mut().filter(|(sender, _)| !sender.is_closed()) {
+                *last_used = Instant::now();
+                return Ok(Pooled {
+                    sender: UpstreamSender::Http2(sender.clone()),
+                    reused: true,
+                    timings: ConnectTimings::default(),
+                    key,
+                    permit: None,
+                });
+            }
+            host.limit.clone()
+        };
+
+        let permit = limit.acquire_owned().await.expect("pool semaphores are never closed");
+        if let Some(sender) = self.take_idle(&key) {
+            return Ok(Pooled {
+                sender: UpstreamSender::Http1(sender),
+                reused: true,
+                timings: ConnectTimings::default(),
+                key,
+                permit: Some(permit),
+            });
+        }
+        self.connect(key, permit, connect).await
+    }
+
+    // Replace a connection that turned out to be closed, keeping its slot
+    pub async fn reconnect<F, Fut>(&self, stale: Pooled, connect: F) -> Result<Pooled, BoxError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<(UpstreamSender, ConnectTimings), BoxError>>,
+    {
+        let permit = match stale.permit {
+            Some(permit) => permit,
+            None => {
+                let limit = self.limit(&stale.key).ok_or("connection pool is poisoned")?;
+                limit.acquire_owned().await.expect("pool semaphores are never closed")
+            }
+        };
+        self.connect(stale.key, permit, connect).await
+    }
+
+    async fn connect<F, Fut>(&self, key: PoolKey, permit: OwnedSemaphorePermit, connect: F) -> Result<Pooled, BoxError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<(UpstreamSender, ConnectTimings), BoxError>>,
+    {
+        let (sender, timings) = connect().await?;
+        if let UpstreamSender::Http2(shared) = &sender {
+            if let Ok(mut hosts) = self.hosts.lock() {
+                hosts.entry(key.clone()).or_default().shared = Some((shared.clone(), Instant::now()));
+            }
+            return Ok(Pooled {
+                sender,
+                reused: false,
+                timings,
+                key,
+                permit: None,
+            });
+        }
+        Ok(Pooled {
+            sender,
+            reused: false,
+            timings,
+            key,
+            permit: Some(permit),
+        })
+    }
+
+    fn limit(&self, key: &PoolKey) -> Option<Arc<Semaphore>> {
+        let mut hosts = self.hosts.lock().ok()?;
+        Some(hosts.entry(key.clone()).or_default().limit.clone())
+    }
+
+    fn take_idle(&self, key: &PoolKey) -> Option<http1::SendRequest<Body>> {
+        let mut hosts = self.hosts.lock().ok()?;
+        let host = hosts.get_mut(key)?;
+        while let Some((sender, since)) = host.idle.pop() {
+            if since.elapsed() < IDLE_TIMEOUT && sender.is_ready() {
+                return Some(sender);
+            }
+        }
+        None
+    }
+
+    // Return an HTTP/1 connection to the pool once its response has been
+    // read, unless either side closed it. HTTP/2 connections stay shared.
+    pub fn release(self: &Arc<Self>, pooled: Pooled) {
+        let Pooled {
+            sender: UpstreamSender::Http1(mut sender),
+            key,
+            permit,
+            ..
+        } = pooled
+        else {
+            return;
+        };
+
+        self.start_sweeper();
+        let pool = Arc::downgrade(self);
+        tokio::spawn(async move {
+            if sender.ready().await.is_err() {
+                return;
+            }
+            if let Some(pool) = pool.upgrade()
+                && let Ok(mut hosts) = pool.hosts.lock()
+            {
+                hosts.entry(key).or_default().idle.push((sender, Instant::now()));
+            }
+            // Only now, so a waiting request finds the idle connection
+            drop(permit);
+        });
+    }
+
+    // Close every idle connection. Hosts still in use keep their limit, so
+    // connections opened afterwards still count against it.
+    pub fn clear(&self) {
+        if let Ok(mut hosts) = self.hosts.lock() {
+            hosts.retain(|_, host| {
+                host.idle.clear();
+                host.shared = None;
+                host.in_use()
+            });
+        }
+    }
+
+    fn start_sweeper(self: &Arc<Self>) {
+        if self.sweeping.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let pool: Weak<Self> = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+            loop {
+                interval.tick().await;
+                let Some(pool) = pool.upgrade() else {
+                    return;
+                };
+                pool.sweep();
+            }
+        });
+    }
+
+    // Drop expired and closed connections, and hosts with nothing left
+    fn sweep(&self) {
+        let Ok(mut hosts) = self.hosts.lock() else {
+            return;
+        };
+        hosts.retain(|_, host| {
+            host.idle
+                .retain(|(sender, since)| since.elapsed() < IDLE_TIMEOUT && !sender.is_closed());
+            if host
+                .shared
+                .as_ref()
+                .is_some_and(|(sender, last_used)| last_used.elapsed() >= IDLE_TIMEOUT || sender.is_closed())
+            {
+                host.shared = None;
+            }
+            host.in_use() || !host.idle.is_empty() || host.shared.is_some()
+        });
+    }
+}
@@ -0,0 +1,63 @@
+use hyper::Uri;
+
+use super::matcher::PathPattern;
+
+// Destination of a map-remote rule, e.g. `http://localhost:9000/*`. The
+// path is a base: what the rule's path pattern matched is replaced by it.
+#[derive(Clone, Debug)]
+pub struct RemoteTarget {
+    pub https: bool,
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl RemoteTarget {
+    pub fn parse(url: &str) -> Result<Self, String> {
+        let url = url.trim();
+        if url.is_empty() {
+            return Err("远程地址不能为空".to_string());
+        }
+
+        let uri: Uri = url.parse().map_err(|e| format!("远程地址无效: {}", e))?;
+        let https = match uri.scheme_str() {
+            Some(scheme) if scheme.eq_ignore_ascii_case("https") => true,
+            Some(scheme) if scheme.eq_ignore_ascii_case("http") => false,
+            _ => return Err("远程地址需以 http:// 或 https:// 开头".to_string()),
+        };
+        let host = uri.host().filter(|host| !host.is_empty()).ok_or("远程地址缺少主机名")?;
+        if uri.query().is_some() {
+            return Err("远程地址不支持查询参数".to_string());
+        }
+
+        Ok(Self {
+            https,
+            host: host.to_string(),
+            port: uri.port_u16().unwrap_or(if https { 443 } else { 80 }),
+            path: uri.path().trim_end_matches('*').trim_end_matches('/').to_string(),
+        })
+    }
+
+    pub fn addr(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    // Value for the Host header, without the scheme's default port
+    pub fn authority(&self) -> String {
+        let default_port = if self.https { 443 } else { 80 };
+        if self.port == default_port {
+            self.host.clone()
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+
+    // `/v2/users` matched by `/v2/*` maps to `{path}/users`; rules without a
+    // path keep the whole request path under the base
+    pub fn map_path(&self, pattern: &PathPattern, path: &str) -> String {
+        match pattern {
+            PathPattern::Any => format!("{}{}", self.path, path),
+            pattern => pattern.rewrite(path, &self.path),
+        }
+    }
+}
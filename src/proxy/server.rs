@@ -1,23 +1,146 @@
-use std::net::SocketAddr;
-use std::sync::{Arc, Mutex, RwLock};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::Instant;
 use tokio::sync::mpsc;
 
 use bytes::Bytes;
 use http_body_util::{combinators::BoxBody, BodyExt, Empty, Full};
-use hyper::server::conn::http1;
+use hyper::client::conn::{http1, http2};
+use hyper::http::uri::Authority;
 use hyper::service::service_fn;
-use hyper::{Method, Request, Response, StatusCode};
-use hyper_util::rt::TokioIo;
-use tokio::net::{TcpListener, TcpStream};
+use hyper::{Method, Request, Response, StatusCode, Version};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use rustls::pki_types::ServerName;
+use rustls::ServerConfig;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
 use tokio::sync::watch;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use super::auth::{self, Credentials};
+use super::balance::{self, Balancer, HealthEvent, Lease, Strategy};
+use super::breakpoint::{BreakpointEvent, BreakpointHub, CompiledBreakpoint, Breakpoint, Decision, PausedMessage, Resolution};
+use super::ca::CertificateAuthority;
+use super::capture::{ConnectTimings, Exchange, Recorder, Timings};
+use super::chain::{Bypass, ProxyChoice, ProxyKind, UpstreamProxy};
+use super::breakpoint;
+use super::cors;
+use super::dns::{self, CompiledDnsOverride, DnsOverride};
+use super::local;
+use super::nameserver::{self, RCODE_REFUSED, RCODE_SERVFAIL};
+use super::pac;
+use super::remote::RemoteTarget;
+use super::socks::{self, Reply};
+use super::headers::{self, CompiledHeaderRewrite, HeaderRewrite, Phase};
+use super::matcher::{strip_brackets, Allowlist, DomainPattern, PathPattern};
+use super::pool::{Pool, PoolKey, UpstreamSender};
+use super::throttle::{self, Conditions, Link, ThrottledStream};
+use super::tls;
+use super::websocket::{self, WebSocketEvent, WebSocketHub};
 
 #[derive(Clone, Debug)]
 pub struct Rule {
     pub id: String,
     pub domain: String,
+    pub domain_regex: bool,
+    pub path: String,
+    pub path_mode: String,
+    pub rewrite_path: bool,
+    pub path_rewrite: String,
+    // One or more targets separated by commas, each with an optional `weight=N`
     pub target: String,
     pub protocol: String,
     pub enabled: bool,
+    pub skip_verify: bool,
+    // Origins allowed when CORS injection is on, see `cors::origin_allowed`
+    pub cors_origins: String,
+    // "forward" proxies to `target`, "local" answers with the map-local response below
+    pub action: String,
+    // Served from this file when set, otherwise from `local_body`
+    pub local_file: String,
+    pub local_body: String,
+    pub local_status: u16,
+    // One `Name: value` header per line
+    pub local_headers: String,
+    // Guessed from the file extension when empty
+    pub local_content_type: String,
+    // Destination of "remote" rules, e.g. `http://localhost:9000/*`
+    pub remote_url: String,
+    // Send the client's Host header to the remote instead of the remote's own
+    pub keep_host: bool,
+    // Network profile for matching requests, "global" = the one from settings, see `throttle::profile`
+    pub throttle: String,
+    // Empty = the global upstream proxy, "direct", or a proxy URL, see `chain::ProxyChoice`
+    pub upstream_proxy: String,
+    // Text WebSocket messages matching this regex are dropped, empty = none
+    pub ws_drop: String,
+    // How requests are spread over several targets, see `balance::Strategy`
+    pub balance: String,
+    // Targets are probed with a GET of this path, empty = no health checks
+    pub health_path: String,
+    // Seconds between health checks
+    pub health_interval: u32,
+}
+
+// A rule together with its parsed domain and path patterns
+#[derive(Clone, Debug)]
+struct CompiledRule {
+    rule: Rule,
+    pattern: DomainPattern,
+    path: PathPattern,
+    remote: Option<RemoteTarget>,
+    chain: ProxyChoice,
+    // Picks among the targets of forwarding rules
+    balancer: Option<Arc<Balancer>>,
+}
+
+// Where a request is sent, and how to re-encrypt towards it
+#[derive(Clone, Debug)]
+struct Upstream {
+    addr: String,
+    tls: Option<UpstreamTls>,
+    // Rewritten request path, when the matched rule rewrites it
+    path: Option<String>,
+    // Host header to send instead of the client's
+    host: Option<String>,
+    // Speak HTTP/2 without TLS (prior knowledge)
+    h2c: bool,
+    // The rule this upstream came from, if any
+    rule: Option<Rule>,
+    // Upstream proxy to reach `addr` through
+    chain: ProxyChoice,
+    // Counts the request against the picked target until the response is done
+    lease: Option<Arc<Lease>>,
+    // Address to connect to instead of resolving `addr`, from a DNS override
+    resolved: Option<IpAddr>,
+}
+
+#[derive(Clone, Debug)]
+struct UpstreamTls {
+    server_name: String,
+    verify: bool,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ProxySettings {
+    pub intercept_https: bool,
+    pub auto_issue_certs: bool,
+    pub cors_enabled: bool,
+    // Global network profile and the values behind its "custom" entry
+    pub throttle_profile: String,
+    pub custom_throttle: Conditions,
+    // Outgoing connections go through this proxy, except to hosts in the bypass list
+    pub upstream_proxy: Option<UpstreamProxy>,
+    pub proxy_bypass: Bypass,
+    // Clients allowed to connect to the listeners, checked as they connect
+    pub client_allowlist: Allowlist,
+    // Clients must send these with Basic proxy authentication when set
+    pub proxy_auth: Option<Credentials>,
+    // The name server forwards queries for other domains here, or refuses them when None
+    pub dns_upstream: Option<SocketAddr>,
+    // Hosts the PAC script sends direct even when a rule matches them
+    pub pac_bypass: Bypass,
 }
 
 #[derive(Clone, Debug)]
@@ -28,45 +151,311 @@ pub struct LogEvent {
     pub url: String,
     pub status: String,
     pub status_code: i32,
+    // Where the request was actually sent, empty when it wasn't forwarded
+    pub upstream_url: String,
+    // Headers, bodies and timings; None for system messages
+    pub exchange: Option<Exchange>,
+}
+
+// How a request reached the proxy
+#[derive(Clone, Debug)]
+enum Inbound {
+    // Plain HTTP, either proxy-style or addressed directly to the HTTP port
+    Plain,
+    // Decrypted from an intercepted CONNECT tunnel to this authority
+    Intercepted(Authority),
+    // TLS terminated on the HTTPS listener (reverse proxy mode)
+    Terminated,
+    // Plain HTTP inside a SOCKS tunnel to this authority
+    Socks(Authority),
+}
+
+// How long a SOCKS connection may stay silent before it is relayed without
+// looking for HTTP or TLS
+const SOCKS_SNIFF_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(300);
+
+// SOCKS clients must finish the handshake within this time
+const SOCKS_HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+// DNS-over-TCP connections without a query for this long are closed
+const DNS_TCP_IDLE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+// Shared state handed to every connection
+#[derive(Clone)]
+struct ProxyContext {
+    rules: Arc<RwLock<Vec<CompiledRule>>>,
+    header_rewrites: Arc<RwLock<Vec<CompiledHeaderRewrite>>>,
+    dns_overrides: Arc<RwLock<Vec<CompiledDnsOverride>>>,
+    breakpoints: Arc<RwLock<Vec<CompiledBreakpoint>>>,
+    breakpoint_hub: Arc<BreakpointHub>,
+    websocket_hub: Arc<WebSocketHub>,
+    settings: Arc<RwLock<ProxySettings>>,
+    ca: Arc<CertificateAuthority>,
+    log_sender: mpsc::Sender<LogEvent>,
+    pool: Arc<Pool>,
+    // Throttling state of the client connection being served
+    link: Arc<Link>,
+    // Address of that client, for the log
+    client_addr: String,
 }
 
 pub struct ProxyServer {
     shutdown_tx: Arc<Mutex<Option<watch::Sender<bool>>>>,
-    rules: Arc<RwLock<Vec<Rule>>>,
+    rules: Arc<RwLock<Vec<CompiledRule>>>,
+    header_rewrites: Arc<RwLock<Vec<CompiledHeaderRewrite>>>,
+    dns_overrides: Arc<RwLock<Vec<CompiledDnsOverride>>>,
+    breakpoints: Arc<RwLock<Vec<CompiledBreakpoint>>>,
+    breakpoint_hub: Arc<BreakpointHub>,
+    websocket_hub: Arc<WebSocketHub>,
+    settings: Arc<RwLock<ProxySettings>>,
+    ca: Arc<CertificateAuthority>,
     log_sender: mpsc::Sender<LogEvent>,
+    pool: Arc<Pool>,
+    health_sender: mpsc::Sender<HealthEvent>,
+    // Running health checks, one per checked balancer, see `sync_health_checks`
+    health_checks: Mutex<Vec<(Weak<Balancer>, tokio::task::AbortHandle)>>,
 }
 
 impl ProxyServer {
-    pub fn new(log_sender: mpsc::Sender<LogEvent>) -> Self {
+    pub fn new(
+        log_sender: mpsc::Sender<LogEvent>,
+        breakpoint_sender: mpsc::Sender<BreakpointEvent>,
+        websocket_sender: mpsc::Sender<WebSocketEvent>,
+        health_sender: mpsc::Sender<HealthEvent>,
+        ca: Arc<CertificateAuthority>,
+    ) -> Self {
         Self {
             shutdown_tx: Arc::new(Mutex::new(None)),
             rules: Arc::new(RwLock::new(Vec::new())),
+            header_rewrites: Arc::new(RwLock::new(Vec::new())),
+            dns_overrides: Arc::new(RwLock::new(Vec::new())),
+            breakpoints: Arc::new(RwLock::new(Vec::new())),
+            breakpoint_hub: Arc::new(BreakpointHub::new(breakpoint_sender)),
+            websocket_hub: Arc::new(WebSocketHub::new(websocket_sender)),
+            settings: Arc::new(RwLock::new(ProxySettings::default())),
+            ca,
             log_sender,
+            pool: Arc::new(Pool::default()),
+            health_sender,
+            health_checks: Mutex::new(Vec::new()),
         }
     }
 
     pub fn update_rules(&self, new_rules: Vec<Rule>) {
+        // Balancers of rules whose targets didn't change keep their state and health checks
+        let previous: Vec<(Rule, Arc<Balancer>)> = self.rules.read()
+            .map(|rules| rules.iter()
+                .filter_map(|compiled| Some((compiled.rule.clone(), compiled.balancer.clone()?)))
+                .collect())
+            .unwrap_or_default();
+
+        let compiled: Vec<CompiledRule> = new_rules
+            .into_iter()
+            .filter_map(|rule| {
+                let compiled = DomainPattern::parse(&rule.domain, rule.domain_regex)
+                    .and_then(|pattern| Ok((pattern, PathPattern::parse(&rule.path, &rule.path_mode)?)))
+                    .and_then(|(pattern, path)| {
+                        let remote = match rule.action.as_str() {
+                            "remote" => Some(RemoteTarget::parse(&rule.remote_url)?),
+                            _ => None,
+                        };
+                        websocket::drop_pattern(&rule.ws_drop)?;
+                        let balancer = match rule.action.as_str() {
+                            "local" | "remote" => None,
+                            _ => Some(balancer_for(&rule, &previous)?),
+                        };
+                        Ok((pattern, path, remote, ProxyChoice::parse(&rule.upstream_proxy)?, balancer))
+                    });
+                match compiled {
+                    Ok((pattern, path, remote, chain, balancer)) => Some(CompiledRule { rule, pattern, path, remote, chain, balancer }),
+                    Err(e) => {
+                        println!("Skipping rule {} ({}): {}", rule.id, rule.domain, e);
+                        None
+                    }
+                }
+            })
+            .collect();
+
         if let Ok(mut rules) = self.rules.write() {
-            *rules = new_rules;
+            *rules = compiled;
             println!("Updated proxy rules: {} rules active", rules.len());
         }
+        // Started once the rules are in place, since the checks look their rule up there
+        self.sync_health_checks();
     }
 
-    pub async fn start(&self, ports: Vec<u16>) -> Result<(), Box<dyn std::error::Error>> {
-        let mut unique_ports = ports;
-        unique_ports.sort_unstable();
-        unique_ports.dedup();
+    // While the proxy runs, every balancer with a health check path has one
+    // task probing it; tasks of balancers the rules no longer use are stopped.
+    // Unchanged balancers keep their task, and nothing is probed while stopped.
+    fn sync_health_checks(&self) {
+        let running = self.shutdown_tx.lock().is_ok_and(|tx| tx.is_some());
+        let checked: Vec<Arc<Balancer>> = match self.rules.read() {
+            Ok(rules) if running => rules
+                .iter()
+                .filter(|compiled| !compiled.rule.health_path.trim().is_empty())
+                .filter_map(|compiled| compiled.balancer.clone())
+                .collect(),
+            _ => Vec::new(),
+        };
 
-        if unique_ports.is_empty() {
-            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "No proxy ports provided").into());
+        let Ok(mut tasks) = self.health_checks.lock() else {
+            return;
+        };
+        tasks.retain(|(balancer, task)| {
+            let keep = checked.iter().any(|other| Weak::as_ptr(balancer) == Arc::as_ptr(other));
+            if !keep {
+                task.abort();
+            }
+            keep
+        });
+        for balancer in checked {
+            if !tasks.iter().any(|(other, _)| Weak::as_ptr(other) == Arc::as_ptr(&balancer)) {
+                let task = tokio::spawn(health_checks(self.context(), Arc::downgrade(&balancer), self.health_sender.clone()));
+                tasks.push((Arc::downgrade(&balancer), task.abort_handle()));
+            }
+        }
+    }
+
+    pub fn update_header_rewrites(&self, new_rewrites: Vec<HeaderRewrite>) {
+        let compiled = new_rewrites
+            .into_iter()
+            .filter(|rewrite| rewrite.enabled)
+            .filter_map(|rewrite| match CompiledHeaderRewrite::compile(&rewrite) {
+                Ok(compiled) => Some(compiled),
+                Err(e) => {
+                    println!("Skipping header rewrite {} ({}): {}", rewrite.id, rewrite.name, e);
+                    None
+                }
+            })
+            .collect();
+
+        if let Ok(mut rewrites) = self.header_rewrites.write() {
+            *rewrites = compiled;
+            println!("Updated header rewrites: {} rewrites active", rewrites.len());
+        }
+    }
+
+    pub fn update_dns_overrides(&self, new_overrides: Vec<DnsOverride>) {
+        let compiled = new_overrides
+            .into_iter()
+            .filter(|entry| entry.enabled)
+            .filter_map(|entry| match CompiledDnsOverride::compile(&entry) {
+                Ok(compiled) => Some(compiled),
+                Err(e) => {
+                    println!("Skipping DNS override {} ({}): {}", entry.id, entry.domain, e);
+                    None
+                }
+            })
+            .collect();
+
+        if let Ok(mut overrides) = self.dns_overrides.write() {
+            *overrides = compiled;
+            println!("Updated DNS overrides: {} overrides active", overrides.len());
+        }
+    }
+
+    pub fn update_breakpoints(&self, new_breakpoints: Vec<Breakpoint>) {
+        let compiled = new_breakpoints
+            .into_iter()
+            .filter(|breakpoint| breakpoint.enabled)
+            .filter_map(|breakpoint| match CompiledBreakpoint::compile(&breakpoint) {
+                Ok(compiled) => Some(compiled),
+                Err(e) => {
+                    println!("Skipping breakpoint {} ({}): {}", breakpoint.id, breakpoint.domain, e);
+                    None
+                }
+            })
+            .collect();
+
+        if let Ok(mut breakpoints) = self.breakpoints.write() {
+            *breakpoints = compiled;
+            println!("Updated breakpoints: {} breakpoints active", breakpoints.len());
+        }
+    }
+
+    // Release a paused request or response; false if it already timed out
+    pub fn resolve_breakpoint(&self, id: u64, decision: Decision) -> bool {
+        self.breakpoint_hub.resolve(id, decision)
+    }
+
+    // Send a text message on a proxied WebSocket; false once it has closed
+    pub fn send_websocket_message(&self, connection: u64, direction: websocket::Direction, text: String) -> bool {
+        self.websocket_hub.inject(connection, direction, text)
+    }
+
+    pub fn update_settings(&self, new_settings: ProxySettings) {
+        if let Ok(mut settings) = self.settings.write() {
+            *settings = new_settings;
+            println!("Updated proxy settings: {:?}", settings);
+        }
+    }
+
+    fn context(&self) -> ProxyContext {
+        ProxyContext {
+            rules: self.rules.clone(),
+            header_rewrites: self.header_rewrites.clone(),
+            dns_overrides: self.dns_overrides.clone(),
+            breakpoints: self.breakpoints.clone(),
+            breakpoint_hub: self.breakpoint_hub.clone(),
+            websocket_hub: self.websocket_hub.clone(),
+            settings: self.settings.clone(),
+            ca: self.ca.clone(),
+            log_sender: self.log_sender.clone(),
+            pool: self.pool.clone(),
+            link: Arc::new(Link::default()),
+            client_addr: String::new(),
+        }
+    }
+
+    // Certificates for the HTTPS listener are issued per SNI name; with
+    // auto-issue off only rule domains get one
+    fn listener_tls_config(&self) -> Arc<ServerConfig> {
+        let rules = self.rules.clone();
+        let settings = self.settings.clone();
+        tls::sni_server_config(self.ca.clone(), move |host| {
+            let auto_issue = settings.read().map(|s| s.auto_issue_certs).unwrap_or(true);
+            auto_issue
+                || rules
+                    .read()
+                    .map(|rules| rules.iter().any(|compiled| compiled.rule.enabled && compiled.pattern.matches(host)))
+                    .unwrap_or(false)
+        })
+    }
+
+    // Listen on `bind` (e.g. 127.0.0.1, 0.0.0.0 or ::) at each port. The
+    // name server, when on, takes both UDP and TCP at `dns_port`.
+    pub async fn start(
+        &self,
+        bind: IpAddr,
+        http_port: u16,
+        https_port: u16,
+        socks_port: Option<u16>,
+        dns_port: Option<u16>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut ports = vec![(http_port, Listener::Http)];
+        if https_port != http_port {
+            ports.push((https_port, Listener::Https));
+        }
+        if let Some(socks_port) = socks_port {
+            ports.push((socks_port, Listener::Socks));
+        }
+        if let Some(dns_port) = dns_port {
+            ports.push((dns_port, Listener::Dns));
+        }
+        if let Some(clash) = port_clash(&ports) {
+            return Err(clash.into());
         }
 
         let mut listeners = Vec::new();
-        for port in unique_ports {
-            let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        for (port, kind) in ports {
+            let addr = SocketAddr::new(bind, port);
             let listener = TcpListener::bind(addr).await?;
-            listeners.push((port, addr, listener));
+            listeners.push((port, addr, listener, kind));
         }
+        let dns_socket = match dns_port {
+            Some(port) => Some((port, Arc::new(UdpSocket::bind(SocketAddr::new(bind, port)).await?))),
+            None => None,
+        };
 
         let (shutdown_tx, shutdown_rx) = watch::channel(false);
         if let Ok(mut shutdown_guard) = self.shutdown_tx.lock() {
@@ -75,23 +464,39 @@ impl ProxyServer {
             }
             *shutdown_guard = Some(shutdown_tx);
         }
+        self.sync_health_checks();
 
         let mut listener_tasks = Vec::new();
-        for (port, addr, listener) in listeners {
-            println!("Proxy server listening on http://{}", addr);
+        for (port, addr, listener, kind) in listeners {
+            println!("Proxy server listening on {}://{}", kind.scheme(), addr);
 
             let _ = self.log_sender.send(LogEvent {
-                time: time::OffsetDateTime::now_utc().format(&time::format_description::parse("[hour]:[minute]:[second]")?).unwrap(),
+                time: log_time(),
                 method: "SYSTEM".to_string(),
                 protocol: "SYSTEM".to_string(),
-                url: format!("Proxy server started on port {}", port),
+                url: format!("Proxy server started on {}", addr),
                 status: "OK".to_string(),
                 status_code: 200,
+                upstream_url: String::new(),
+                exchange: None,
             }).await;
 
+            if kind == Listener::Https && !self.ca.has_ca_cert() {
+                let _ = self.log_sender.send(LogEvent {
+                    time: log_time(),
+                    method: "SYSTEM".to_string(),
+                    protocol: "SYSTEM".to_string(),
+                    url: format!("HTTPS listener on port {} needs a CA certificate to serve TLS", port),
+                    status: "No CA".to_string(),
+                    status_code: 500,
+                    upstream_url: String::new(),
+                    exchange: None,
+                }).await;
+            }
+
             let mut stop_signal = shutdown_rx.clone();
-            let rules = self.rules.clone();
-            let log_sender = self.log_sender.clone();
+            let ctx = self.context();
+            let tls_config = (kind == Listener::Https).then(|| self.listener_tls_config());
 
             let task = tokio::spawn(async move {
                 loop {
@@ -112,21 +517,15 @@ impl ProxyServer {
                         }
                         result = listener.accept() => {
                             match result {
+                                Ok((_, peer)) if !client_allowed(&ctx, peer) => reject_client(&ctx, peer, port),
+                                Ok((stream, _)) if kind == Listener::Socks => {
+                                    tokio::task::spawn(serve_socks(stream, ctx.clone()));
+                                }
+                                Ok((stream, _)) if kind == Listener::Dns => {
+                                    tokio::task::spawn(serve_dns_tcp(stream, ctx.clone()));
+                                }
                                 Ok((stream, _)) => {
-                                    let io = TokioIo::new(stream);
-                                    let rules = rules.clone();
-                                    let log_sender = log_sender.clone();
-                                    tokio::task::spawn(async move {
-                                        if let Err(err) = http1::Builder::new()
-                                            .preserve_header_case(true)
-                                            .title_case_headers(true)
-                                            .serve_connection(io, service_fn(move |req| proxy(req, rules.clone(), log_sender.clone())))
-                                            .with_upgrades()
-                                            .await
-                                        {
-                                            println!("Failed to serve connection: {:?}", err);
-                                        }
-                                    });
+                                    tokio::task::spawn(serve_connection(stream, tls_config.clone(), ctx.clone()));
                                 }
                                 Err(e) => println!("Error accepting connection: {}", e),
                             }
@@ -137,17 +536,23 @@ impl ProxyServer {
 
             listener_tasks.push(task);
         }
+        if let Some((port, socket)) = dns_socket {
+            println!("Name server listening on udp://{}", SocketAddr::new(bind, port));
+            listener_tasks.push(tokio::spawn(serve_dns_udp(socket, port, bind, self.context(), shutdown_rx.clone())));
+        }
 
         let mut shutdown_main = shutdown_rx.clone();
         let _ = shutdown_main.changed().await;
         println!("Proxy server stopping...");
         let _ = self.log_sender.send(LogEvent {
-            time: time::OffsetDateTime::now_utc().format(&time::format_description::parse("[hour]:[minute]:[second]")?).unwrap(),
+            time: log_time(),
             method: "SYSTEM".to_string(),
             protocol: "SYSTEM".to_string(),
             url: "Proxy server stopped".to_string(),
             status: "OK".to_string(),
             status_code: 200,
+            upstream_url: String::new(),
+            exchange: None,
         }).await;
 
         for task in listener_tasks {
@@ -163,156 +568,1485 @@ impl ProxyServer {
         {
             let _ = tx.send(true);
         }
+        self.sync_health_checks();
+        self.pool.clear();
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Listener {
+    Http,
+    Https,
+    Socks,
+    // The name server's TCP side
+    Dns,
+}
+
+impl Listener {
+    fn scheme(self) -> &'static str {
+        match self {
+            Listener::Http => "http",
+            Listener::Https => "https",
+            Listener::Socks => "socks5",
+            Listener::Dns => "dns",
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Listener::Http => "HTTP",
+            Listener::Https => "HTTPS",
+            Listener::Socks => "SOCKS",
+            Listener::Dns => "DNS",
+        }
+    }
+}
+
+// Two listeners given the same port, which only HTTP and HTTPS may share
+fn port_clash(ports: &[(u16, Listener)]) -> Option<String> {
+    ports.iter().enumerate().find_map(|(i, (port, kind))| {
+        let (_, other) = ports[..i].iter().find(|(other_port, _)| other_port == port)?;
+        Some(format!("{} 端口和 {} 端口都是 {}，请为它们设置不同的端口", other.name(), kind.name(), port))
+    })
+}
+
+// Answer DNS queries arriving over UDP until the proxy stops
+async fn serve_dns_udp(socket: Arc<UdpSocket>, port: u16, bind: IpAddr, ctx: ProxyContext, mut stop_signal: watch::Receiver<bool>) {
+    let mut buf = vec![0u8; nameserver::MAX_UDP_MESSAGE];
+    loop {
+        let (len, peer) = tokio::select! {
+            changed = stop_signal.changed() => {
+                if changed.is_err() || *stop_signal.borrow() {
+                    println!("Name server on port {} stopping...", port);
+                    break;
+                }
+                continue;
+            }
+            result = socket.recv_from(&mut buf) => match result {
+                Ok(received) => received,
+                Err(e) => {
+                    println!("Error receiving DNS query: {}", e);
+                    continue;
+                }
+            },
+        };
+        if !client_allowed(&ctx, peer) {
+            reject_client(&ctx, peer, port);
+            continue;
+        }
+
+        let msg = buf[..len].to_vec();
+        let socket = socket.clone();
+        let ctx = ctx.clone();
+        // A wildcard bind doesn't say which of our addresses the client used
+        let local = if bind.is_unspecified() { local_ip_towards(peer.ip()).unwrap_or(bind) } else { bind };
+        tokio::spawn(async move {
+            if let Some(reply) = dns_reply(&ctx, &msg, local, false).await {
+                let _ = socket.send_to(&reply, peer).await;
+            }
+        });
+    }
+}
+
+// Answer DNS queries over one TCP connection, each framed with its length
+async fn serve_dns_tcp(mut stream: TcpStream, ctx: ProxyContext) {
+    let Ok(local) = stream.local_addr().map(|addr| addr.ip().to_canonical()) else {
+        return;
+    };
+    loop {
+        let msg = match tokio::time::timeout(DNS_TCP_IDLE_TIMEOUT, nameserver::read_tcp_message(&mut stream)).await {
+            Ok(Ok(msg)) => msg,
+            _ => return,
+        };
+        let Some(reply) = dns_reply(&ctx, &msg, local, true).await else {
+            return;
+        };
+        if nameserver::write_tcp_message(&mut stream, &reply).await.is_err() {
+            return;
+        }
+    }
+}
+
+// Our address on the route to `peer`. Connecting a UDP socket only picks the
+// route; nothing is sent.
+fn local_ip_towards(peer: IpAddr) -> Option<IpAddr> {
+    let peer = peer.to_canonical();
+    let unspecified: IpAddr = if peer.is_ipv4() { std::net::Ipv4Addr::UNSPECIFIED.into() } else { std::net::Ipv6Addr::UNSPECIFIED.into() };
+    let socket = std::net::UdpSocket::bind((unspecified, 0)).ok()?;
+    socket.connect((peer, 9)).ok()?;
+    Some(socket.local_addr().ok()?.ip())
+}
+
+// Reply to one DNS message. Domains of enabled rules resolve to `local`, the
+// address the client reached the proxy at; other names are forwarded to the
+// upstream server, or refused without one. None when the message can't be
+// answered at all.
+async fn dns_reply(ctx: &ProxyContext, msg: &[u8], local: IpAddr, tcp: bool) -> Option<Vec<u8>> {
+    let Some(query) = nameserver::parse_query(msg) else {
+        return nameserver::format_error(msg);
+    };
+
+    let rule_id = ctx.rules.read().ok().and_then(|rules| {
+        rules.iter()
+            .find(|compiled| compiled.rule.enabled && compiled.pattern.matches(&query.name))
+            .map(|compiled| compiled.rule.id.clone())
+    });
+    if let Some(rule_id) = rule_id {
+        let addrs: Vec<IpAddr> = [local].into_iter().filter(|addr| query.wants(*addr)).collect();
+        println!("DNS {} {} answered for rule {}: {:?}", query.type_name(), query.name, rule_id, addrs);
+        let _ = ctx.log_sender.send(LogEvent {
+            time: log_time(),
+            method: query.type_name(),
+            protocol: "DNS".to_string(),
+            url: query.name.clone(),
+            status: "DNS Answered".to_string(),
+            status_code: 200,
+            upstream_url: addrs.iter().map(|addr| addr.to_string()).collect::<Vec<_>>().join(", "),
+            exchange: None,
+        }).await;
+        return Some(nameserver::answer(&query, &addrs));
+    }
+
+    let upstream = ctx.settings.read().ok().and_then(|settings| settings.dns_upstream);
+    let Some(upstream) = upstream else {
+        return Some(nameserver::error(&query, RCODE_REFUSED));
+    };
+    match nameserver::forward(upstream, msg, tcp).await {
+        Ok(reply) => Some(reply),
+        Err(e) => {
+            println!("DNS forward error for {} to {}: {}", query.name, upstream, e);
+            Some(nameserver::error(&query, RCODE_SERVFAIL))
+        }
+    }
+}
+
+// Per-connection context: everything sent to and from the client, tunnels
+// included, goes through the returned link
+fn connection_context(stream: &TcpStream, ctx: ProxyContext) -> (ProxyContext, Arc<Link>) {
+    let link = Arc::new(Link::default());
+    let client_addr = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_default();
+    (ProxyContext { link: link.clone(), client_addr, ..ctx }, link)
+}
+
+async fn serve_connection(stream: TcpStream, tls_config: Option<Arc<ServerConfig>>, ctx: ProxyContext) {
+    let (ctx, link) = connection_context(&stream, ctx);
+
+    // Clients may still use the HTTPS port as a plain proxy, so only connections
+    // opening with a TLS handshake record (0x16) are terminated here
+    let mut first_byte = [0u8; 1];
+    let terminate = match tls_config {
+        Some(config) if matches!(stream.peek(&mut first_byte).await, Ok(1)) && first_byte[0] == 0x16 => Some(config),
+        _ => None,
+    };
+    let stream = ThrottledStream::new(stream, link);
+
+    if let Some(config) = terminate {
+        let stream = match TlsAcceptor::from(config).accept(stream).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                println!("TLS handshake failed: {}", e);
+                return;
+            }
+        };
+
+        if let Err(err) = http_server()
+            .serve_connection_with_upgrades(TokioIo::new(stream), service_fn(move |req| forward(req, ctx.clone(), Inbound::Terminated)))
+            .await
+        {
+            println!("Failed to serve connection: {:?}", err);
+        }
+        return;
+    }
+
+    if let Err(err) = http_server()
+        .serve_connection_with_upgrades(TokioIo::new(stream), service_fn(move |req| proxy(req, ctx.clone())))
+        .await
+    {
+        println!("Failed to serve connection: {:?}", err);
+    }
+}
+
+// Serves HTTP/1.1 and HTTP/2, telling them apart by the HTTP/2 connection
+// preface. That covers TLS connections that negotiated "h2" as well as h2c
+// with prior knowledge.
+fn http_server() -> auto::Builder<TokioExecutor> {
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    builder.http1().preserve_header_case(true).title_case_headers(true);
+    builder
+}
+
+// A SOCKS client. Plain HTTP inside the tunnel is served request by request
+// like the HTTP port, TLS is decrypted when interception is on, and anything
+// else is relayed as is.
+async fn serve_socks(mut stream: TcpStream, ctx: ProxyContext) {
+    let (ctx, link) = connection_context(&stream, ctx);
+    let credentials = ctx.settings.read().ok().and_then(|s| s.proxy_auth.clone());
+    let accepted = tokio::time::timeout(SOCKS_HANDSHAKE_TIMEOUT, socks::accept(&mut stream, credentials.as_ref())).await;
+    let request = match accepted {
+        Ok(Ok(request)) => request,
+        Ok(Err(e)) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            println!("SOCKS authentication failed for {}: {}", ctx.client_addr, e);
+            log_auth_failure(&ctx, "SOCKS".to_string(), "SOCKS", e.to_string(), "Proxy Auth Failed").await;
+            return;
+        }
+        Ok(Err(e)) => {
+            println!("SOCKS handshake failed: {}", e);
+            return;
+        }
+        Err(_) => {
+            println!("SOCKS handshake from {} timed out", ctx.client_addr);
+            return;
+        }
+    };
+    let url = request.addr();
+    let Ok(authority) = url.parse::<Authority>() else {
+        println!("SOCKS destination is not a valid authority: {}", url);
+        let _ = socks::reply(&mut stream, request.version, Reply::Failed).await;
+        return;
+    };
+
+    // The client is only told the tunnel is up once its destination answers.
+    // Decrypted tunnels and hosts with a map-local rule are the exception:
+    // their requests may never reach the destination, so it isn't checked.
+    let route = tunnel_route(&ctx, &request.host);
+    let addr = route.addr.clone().unwrap_or_else(|| url.clone());
+    let intercept = intercept_enabled(&ctx, &url);
+    let mut dialed = if intercept || route.local {
+        None
+    } else {
+        Some(dial_tunnel(&ctx, addr.clone(), &route).await)
+    };
+    let reply = match dialed.as_ref().map(|dialed| &dialed.server) {
+        Some(Err(e)) => Reply::for_error(e),
+        _ => Reply::Succeeded,
+    };
+    if let Err(e) = socks::reply(&mut stream, request.version, reply).await {
+        println!("SOCKS reply failed: {}", e);
+        return;
+    }
+    let method = request.version.name().to_string();
+    if let Some(dial) = dialed.take_if(|dial| dial.server.is_err()) {
+        // Logged as unreachable
+        relay_tunnel(stream, &ctx, method, "TCP", url, route, dial).await;
+        return;
+    }
+
+    // HTTP and TLS clients speak first; other protocols may wait for the server
+    let mut first_byte = [0u8; 1];
+    let first = match tokio::time::timeout(SOCKS_SNIFF_TIMEOUT, stream.peek(&mut first_byte)).await {
+        Ok(Ok(1)) => Some(first_byte[0]),
+        _ => None,
+    };
+    let stream = ThrottledStream::new(stream, link);
+
+    match first {
+        Some(0x16) if intercept => decrypt_tunnel(stream, authority, ctx, method, url).await,
+        // Requests are forwarded one by one on connections of their own, so
+        // the one that checked the destination is closed
+        Some(byte) if byte.is_ascii_uppercase() => {
+            if let Err(err) = http_server()
+                .serve_connection_with_upgrades(
+                    TokioIo::new(stream),
+                    service_fn(move |req| forward(req, ctx.clone(), Inbound::Socks(authority.clone()))),
+                )
+                .await
+            {
+                println!("Failed to serve connection: {:?}", err);
+            }
+        }
+        _ => {
+            let protocol = if first == Some(0x16) { "TLS" } else { "TCP" };
+            let dial = match dialed {
+                Some(dial) => dial,
+                None => dial_tunnel(&ctx, addr, &route).await,
+            };
+            relay_tunnel(stream, &ctx, method, protocol, url, route, dial).await;
+        }
     }
 }
 
 async fn proxy(
-    req: Request<hyper::body::Incoming>,
-    rules: Arc<RwLock<Vec<Rule>>>,
-    log_sender: mpsc::Sender<LogEvent>,
+    mut req: Request<hyper::body::Incoming>,
+    ctx: ProxyContext,
 ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+    // Browsers fetch the PAC script without proxy credentials
+    if let Some(resp) = serve_pac(&req, &ctx).await {
+        return Ok(resp);
+    }
+
+    // Before anything else, for tunnels and plain requests alike
+    if let Some(resp) = authenticate(&mut req, &ctx).await {
+        return Ok(resp);
+    }
+
+    if Method::CONNECT != req.method() {
+        return forward(req, ctx, Inbound::Plain).await;
+    }
+
+    // HTTPS Tunneling
     let method = req.method().to_string();
     let url = req.uri().to_string();
-    let protocol = format!("{:?}", req.version());
+    let authority = req.uri().authority().cloned();
+
+    let route = authority.as_ref().map(|auth| tunnel_route(&ctx, auth.host())).unwrap_or_default();
+    let addr = route.addr.clone().or_else(|| host_addr(req.uri()));
+
+    if let (Some(addr), Some(authority)) = (addr, authority) {
+        let intercept = intercept_enabled(&ctx, &url);
+
+        tokio::task::spawn(async move {
+            match hyper::upgrade::on(req).await {
+                Ok(upgraded) => {
+                    let upgraded = TokioIo::new(upgraded);
+                    if intercept {
+                        decrypt_tunnel(upgraded, authority, ctx, method, url).await;
+                    } else {
+                        let dial = dial_tunnel(&ctx, addr, &route).await;
+                        relay_tunnel(upgraded, &ctx, method, "HTTPS", url, route, dial).await;
+                    }
+                }
+                Err(e) => println!("upgrade error: {}", e),
+            }
+        });
+
+        Ok(Response::new(empty()))
+    } else {
+        println!("CONNECT host is not socket addr: {:?}", req.uri());
+        let mut resp = Response::new(full("CONNECT must be to a socket address"));
+        *resp.status_mut() = StatusCode::BAD_REQUEST;
+        Ok(resp)
+    }
+}
+
+// The PAC script for `GET /proxy.pac` addressed to the listener, built from the current rules
+async fn serve_pac(
+    req: &Request<hyper::body::Incoming>,
+    ctx: &ProxyContext,
+) -> Option<Response<BoxBody<Bytes, hyper::Error>>> {
+    if !matches!(*req.method(), Method::GET | Method::HEAD)
+        || req.uri().authority().is_some()
+        || req.uri().path() != pac::PAC_PATH
+    {
+        return None;
+    }
+    // The script points browsers back at the address they fetched it from
+    let proxy = req.headers().get(hyper::header::HOST)?.to_str().ok()?.to_string();
+    let authority: Authority = proxy.parse().ok()?;
+
+    let script = {
+        let rules = ctx.rules.read().ok()?;
+        let enabled = || rules.iter().filter(|compiled| compiled.rule.enabled);
+        if enabled().any(|compiled| compiled.pattern.matches(authority.host())) {
+            return None;
+        }
+        let bypass = ctx.settings.read().ok().map(|settings| settings.pac_bypass.clone()).unwrap_or_default();
+        pac::script(enabled().map(|compiled| (compiled.rule.id.as_str(), &compiled.pattern)), &bypass, &proxy)
+    };
+
+    let _ = ctx.log_sender.send(LogEvent {
+        time: log_time(),
+        method: req.method().to_string(),
+        protocol: "HTTP".to_string(),
+        url: format!("http://{}{}", proxy, pac::PAC_PATH),
+        status: "PAC Served".to_string(),
+        status_code: 200,
+        upstream_url: String::new(),
+        exchange: None,
+    }).await;
+    let resp = Response::builder()
+        .header(hyper::header::CONTENT_TYPE, pac::CONTENT_TYPE)
+        .header(hyper::header::CACHE_CONTROL, "no-cache")
+        .body(full(script))
+        .unwrap();
+    Some(resp)
+}
+
+// A 407 response when the proxy requires credentials and `req` doesn't carry
+// them. Credentials are removed either way, so they never reach an upstream.
+async fn authenticate(
+    req: &mut Request<hyper::body::Incoming>,
+    ctx: &ProxyContext,
+) -> Option<Response<BoxBody<Bytes, hyper::Error>>> {
+    let presented = req.headers_mut().remove(hyper::header::PROXY_AUTHORIZATION);
+    let credentials = ctx.settings.read().ok().and_then(|s| s.proxy_auth.clone())?;
+    let status = match presented.as_ref().map(auth::basic_credentials) {
+        Some(Some((username, password))) if credentials.matches(username.as_bytes(), password.as_bytes()) => return None,
+        Some(Some((username, _))) => {
+            println!("Proxy authentication failed for {} as {:?}", ctx.client_addr, username);
+            "Proxy Auth Failed"
+        }
+        Some(None) => "Proxy Auth Failed",
+        // Clients usually try without credentials first and retry after the challenge
+        None => "Proxy Auth Required",
+    };
+
+    let protocol = if req.method() == Method::CONNECT { "HTTPS" } else { "HTTP" };
+    log_auth_failure(ctx, req.method().to_string(), protocol, req.uri().to_string(), status).await;
+    let resp = Response::builder()
+        .status(StatusCode::PROXY_AUTHENTICATION_REQUIRED)
+        .header(hyper::header::PROXY_AUTHENTICATE, auth::CHALLENGE)
+        .body(full("Proxy Authentication Required"))
+        .unwrap();
+    Some(resp)
+}
+
+// Log a request or connection turned away for lacking valid credentials
+async fn log_auth_failure(ctx: &ProxyContext, method: String, protocol: &str, url: String, status: &str) {
+    let _ = ctx.log_sender.send(LogEvent {
+        time: log_time(),
+        method,
+        protocol: protocol.to_string(),
+        url,
+        status: status.to_string(),
+        status_code: 407,
+        upstream_url: String::new(),
+        exchange: Some(Exchange {
+            client_addr: ctx.client_addr.clone(),
+            started: Some(time::OffsetDateTime::now_utc()),
+            complete: true,
+            ..Default::default()
+        }),
+    }).await;
+}
+
+// Where a blind tunnel to a host goes according to the rules
+#[derive(Clone, Debug, Default)]
+struct TunnelRoute {
+    // None when no rule redirects the host
+    addr: Option<String>,
+    chain: ProxyChoice,
+    conditions: Option<Conditions>,
+    // Held for as long as the tunnel is open
+    lease: Option<Arc<Lease>>,
+    // A map-local rule answers the host's readable requests itself
+    local: bool,
+}
+
+fn tunnel_route(ctx: &ProxyContext, host: &str) -> TunnelRoute {
+    let upstream = match_rule(&ctx.rules, host, None);
+    let local = upstream.as_ref().and_then(|upstream| upstream.rule.as_ref()).is_some_and(|rule| rule.action == "local");
+    // Map-local rules can only answer decrypted requests, so blind tunnels go to the real host
+    let upstream = upstream.filter(|_| !local);
+    TunnelRoute {
+        local,
+        conditions: throttle_conditions(ctx, upstream.as_ref().and_then(|upstream| upstream.rule.as_ref())),
+        chain: upstream.as_ref().map(|upstream| upstream.chain.clone()).unwrap_or_default(),
+        lease: upstream.as_ref().and_then(|upstream| upstream.lease.clone()),
+        addr: upstream.map(|upstream| upstream.addr),
+    }
+}
+
+fn client_allowed(ctx: &ProxyContext, peer: SocketAddr) -> bool {
+    ctx.settings.read().map(|s| s.client_allowlist.allows(peer.ip())).unwrap_or(false)
+}
+
+// Log a connection from a client outside the allowlist; it is closed unanswered
+fn reject_client(ctx: &ProxyContext, peer: SocketAddr, port: u16) {
+    println!("Rejected connection from {} on port {}: not in the client allowlist", peer, port);
+    // Without waiting, so a flood of rejected clients can't hold up the listener
+    let _ = ctx.log_sender.try_send(LogEvent {
+        time: log_time(),
+        method: "SYSTEM".to_string(),
+        protocol: "SYSTEM".to_string(),
+        url: format!("Rejected connection from {} on port {}", peer.ip(), port),
+        status: "Client Not Allowed".to_string(),
+        status_code: 403,
+        upstream_url: String::new(),
+        exchange: None,
+    });
+}
+
+// Whether TLS tunnels should be decrypted rather than relayed
+fn intercept_enabled(ctx: &ProxyContext, url: &str) -> bool {
+    let intercept = ctx.settings.read().map(|s| s.intercept_https).unwrap_or(false);
+    if intercept && !ctx.ca.has_ca_cert() {
+        println!("HTTPS interception is enabled but no CA certificate exists, tunneling {} instead", url);
+    }
+    intercept && ctx.ca.has_ca_cert()
+}
+
+// Decrypt a tunnel to `authority`, logging when the client's handshake fails.
+// Decrypted requests apply their own rule's conditions.
+async fn decrypt_tunnel<I>(io: I, authority: Authority, ctx: ProxyContext, method: String, url: String)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    if let Err(e) = intercept_tunnel(io, authority, ctx.clone()).await {
+        println!("intercept error for {}: {}", url, e);
+        let _ = ctx.log_sender.send(LogEvent {
+            time: log_time(),
+            method,
+            protocol: "HTTPS".to_string(),
+            url,
+            status: "TLS Handshake Failed".to_string(),
+            status_code: 502,
+            upstream_url: String::new(),
+            exchange: None,
+        }).await;
+    }
+}
+
+// Relay a tunnel to `addr` as is, and log it once it closes
+// The connection a relayed tunnel goes through
+struct TunnelDial {
+    addr: String,
+    server: std::io::Result<TcpStream>,
+    dns_override: Option<CompiledDnsOverride>,
+}
+
+async fn dial_tunnel(ctx: &ProxyContext, addr: String, route: &TunnelRoute) -> TunnelDial {
+    let via = upstream_proxy(ctx, &route.chain, &addr);
+    let dns_override = dns_override(ctx, &addr);
+    let dial = dns_override.as_ref().map_or_else(|| addr.clone(), |entry| with_ip(&addr, entry.address));
+    let server = match via {
+        Some(proxy) => proxy.connect(&dial).await,
+        None => TcpStream::connect(dial).await,
+    };
+    TunnelDial { addr, server, dns_override }
+}
+
+// Relay `io` through `dial` and log the tunnel once it closes
+async fn relay_tunnel<I>(
+    io: I,
+    ctx: &ProxyContext,
+    method: String,
+    protocol: &str,
+    url: String,
+    route: TunnelRoute,
+    dial: TunnelDial,
+) where
+    I: AsyncRead + AsyncWrite + Unpin,
+{
+    let time = log_time();
+    let started_at = time::OffsetDateTime::now_utc();
+    let started = std::time::Instant::now();
+    simulate_conditions(ctx, route.conditions, &url).await;
+    let TunnelDial { addr, server, dns_override } = dial;
+    let (status, status_code, sizes, complete) = match server {
+        Ok(server) => match tunnel(io, server).await {
+            Ok(sizes) => ("Tunnel Established", 200, sizes, true),
+            Err(e) => {
+                println!("server io error: {}", e);
+                ("Tunnel Established", 200, (0, 0), false)
+            }
+        },
+        Err(e) => {
+            println!("connect error to {}: {}", addr, e);
+            ("Upstream Unreachable", 502, (0, 0), false)
+        }
+    };
+    // The load-balanced target stops counting the tunnel once it has closed
+    drop(route.lease);
+    let _ = ctx.log_sender.send(LogEvent {
+        time,
+        method,
+        protocol: protocol.to_string(),
+        url,
+        status: status.to_string(),
+        status_code,
+        upstream_url: String::new(),
+        exchange: Some(Exchange {
+            client_addr: ctx.client_addr.clone(),
+            dns_override: dns_override
+                .map(|entry| entry.describe(strip_brackets(target_host(&addr))))
+                .unwrap_or_default(),
+            request_size: sizes.0,
+            response_size: sizes.1,
+            complete,
+            started: Some(started_at),
+            timings: Timings {
+                total: started.elapsed(),
+                ..Default::default()
+            },
+            ..Default::default()
+        }),
+    }).await;
+}
+
+// Forward a plain request upstream, pausing it first when a breakpoint matches
+async fn forward(
+    req: Request<hyper::body::Incoming>,
+    ctx: ProxyContext,
+    inbound: Inbound,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+    let req = req.map(|body| body.boxed());
+    let mut recorder = Recorder::new(log_time(), ctx.client_addr.clone());
+    let (_, _, host) = describe(&req, &inbound);
+    let paused = host
+        .as_deref()
+        .is_some_and(|host| breakpoint_matches(&ctx, Phase::Request, host, req.uri().path()));
+    if !paused {
+        return forward_request(req, ctx, inbound, recorder).await;
+    }
+
+    match pause_request(req, &ctx, &inbound, &mut recorder).await? {
+        Ok(req) => forward_request(req, ctx, inbound, recorder).await,
+        Err(resp) => Ok(resp),
+    }
+}
+
+// URL and protocol for the log, and the host used for rule matching
+fn describe<B>(req: &Request<B>, inbound: &Inbound) -> (String, String, Option<String>) {
+    let host_header = req.headers().get(hyper::header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .map(|h| h.to_string());
+    // HTTP/2 requests carry an absolute URI, HTTP/1 ones usually just the path
+    let target = req.uri().path_and_query().map(|target| target.as_str()).unwrap_or("/");
+    let protocol = |tls: bool| match req.version() {
+        Version::HTTP_2 => "HTTP/2".to_string(),
+        _ if tls => "HTTPS".to_string(),
+        version => format!("{:?}", version),
+    };
+    let (url, protocol) = match inbound {
+        Inbound::Plain => (req.uri().to_string(), protocol(false)),
+        Inbound::Intercepted(authority) => (
+            format!("https://{}{}", display_authority(authority), target),
+            protocol(true),
+        ),
+        Inbound::Terminated => (
+            format!("https://{}{}", host_header.as_deref().or(req.uri().authority().map(|a| a.as_str())).unwrap_or_default(), target),
+            protocol(true),
+        ),
+        Inbound::Socks(authority) => (
+            format!("http://{}{}", host_header.clone().unwrap_or_else(|| authority.to_string()), target),
+            protocol(false),
+        ),
+    };
 
     // Extract host for rule matching
-    let host_str = req.uri().host().map(|h| h.to_string()).or_else(|| {
-        req.headers().get(hyper::header::HOST)
-            .and_then(|h| h.to_str().ok())
-            .map(|h| h.split(':').next().unwrap_or(h).to_string())
+    let host = req.uri().host().map(|h| h.to_string()).or_else(|| {
+        host_header.as_deref().map(|h| h.split(':').next().unwrap_or(h).to_string())
+    }).or_else(|| match inbound {
+        Inbound::Intercepted(authority) | Inbound::Socks(authority) => Some(authority.host().to_string()),
+        _ => None,
     });
 
+    (url, protocol, host)
+}
+
+async fn forward_request(
+    mut req: Request<BoxBody<Bytes, hyper::Error>>,
+    ctx: ProxyContext,
+    inbound: Inbound,
+    mut recorder: Recorder,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+    // HTTP/2 clients send :authority instead of a Host header
+    if !req.headers().contains_key(hyper::header::HOST)
+        && let Some(host) = req.uri().authority().and_then(|authority| hyper::header::HeaderValue::from_str(authority.as_str()).ok())
+    {
+        req.headers_mut().insert(hyper::header::HOST, host);
+    }
+    let (url, protocol, host_str) = describe(&req, &inbound);
+    recorder.method = req.method().to_string();
+    recorder.url = url.clone();
+    recorder.protocol = protocol;
+    recorder.set_request_headers(req.headers());
+    let mut req = recorder.tee_request(req);
+
     // Check for rules match
-    let mut target_addr = None;
-    if let Some(host) = &host_str {
-        if let Ok(rules) = rules.read() {
-            for rule in rules.iter() {
-                if rule.enabled && rule.domain == *host {
-                    println!("Matched rule: {} -> {}", rule.domain, rule.target);
-                    let target = rule.target.clone();
-                    target_addr = Some(if !target.is_empty() && target.chars().all(|c| c.is_numeric()) {
-                        format!("127.0.0.1:{}", target)
-                    } else if !target.is_empty() && !target.contains(':') {
-                        format!("{}:80", target)
-                    } else {
-                        target
-                    });
-                    break;
+    let request_path = req.uri().path().to_string();
+    let target = host_str.as_deref().and_then(|host| match_rule(&ctx.rules, host, Some(&request_path)));
+
+    // Determine upstream address, and whether to re-encrypt towards it
+    let mut upstream = if let Some(target) = target {
+        target
+    } else if let Inbound::Terminated = inbound {
+        // The HTTPS listener only serves rule domains
+        let resp = Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(full(format!("No proxy rule matches host {}", host_str.unwrap_or_default())))
+            .unwrap();
+        return Ok(recorder.finish(&ctx.log_sender, "No Matching Rule".to_string(), String::new(), resp));
+    } else if let Inbound::Intercepted(authority) = &inbound {
+        Upstream {
+            addr: format!("{}:{}", authority.host(), authority.port_u16().unwrap_or(443)),
+            tls: Some(UpstreamTls {
+                server_name: strip_brackets(authority.host()).to_string(),
+                verify: true,
+            }),
+            path: None,
+            host: None,
+            h2c: false,
+            rule: None,
+            chain: ProxyChoice::Global,
+            lease: None,
+            resolved: None,
+        }
+    } else if let Inbound::Socks(authority) = &inbound {
+        // The SOCKS destination, not the Host header, says where the client connected
+        Upstream {
+            addr: format!("{}:{}", authority.host(), authority.port_u16().unwrap_or(80)),
+            tls: None,
+            path: None,
+            host: None,
+            h2c: false,
+            rule: None,
+            chain: ProxyChoice::Global,
+            lease: None,
+            resolved: None,
+        }
+    } else if let Some(host) = req.uri().host() {
+        let port = req.uri().port_u16().unwrap_or(80);
+        Upstream {
+            addr: format!("{}:{}", host, port),
+            tls: None,
+            path: None,
+            host: None,
+            h2c: false,
+            rule: None,
+            chain: ProxyChoice::Global,
+            lease: None,
+            resolved: None,
+        }
+    } else {
+        // Fallback to Host header
+        let addr = req.headers().get(hyper::header::HOST)
+            .and_then(|h| h.to_str().ok())
+            .map(|h| {
+                if h.contains(':') {
+                    h.to_string()
+                } else {
+                    format!("{}:80", h)
                 }
-            }
+            })
+            .unwrap_or_default();
+        Upstream {
+            addr,
+            tls: None,
+            path: None,
+            host: None,
+            h2c: false,
+            rule: None,
+            chain: ProxyChoice::Global,
+            lease: None,
+            resolved: None,
         }
+    };
+    if let Some(rule) = &upstream.rule {
+        recorder.rule_id = rule.id.clone();
     }
+    let conditions = throttle_conditions(&ctx, upstream.rule.as_ref());
+    simulate_conditions(&ctx, conditions, &url).await;
 
-    if Method::CONNECT == req.method() {
-        // HTTPS Tunneling
-        let addr = if let Some(target) = target_addr {
-            Some(target)
-        } else {
-            host_addr(req.uri())
+    // Answer CORS preflights here, so they succeed even if the upstream doesn't handle them
+    let cors_origin = cors_origin(&ctx, &upstream, req.headers());
+    if let Some(origin) = &cors_origin
+        && cors::is_preflight(req.method(), req.headers())
+    {
+        let mut resp = Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(empty())
+            .unwrap();
+        *resp.headers_mut() = cors::preflight_headers(req.headers(), origin);
+        return Ok(recorder.finish(&ctx.log_sender, "CORS Preflight".to_string(), String::new(), resp));
+    }
+
+    let rewrite_host = host_str.unwrap_or_default();
+
+    // Map-local rules answer from a file or inline body without contacting the upstream
+    if let Some(rule) = upstream.rule.as_ref().filter(|rule| rule.action == "local") {
+        let (mut resp, status) = match local::load(rule).await {
+            Ok(local) => {
+                let mut resp = Response::new(full(local.body));
+                *resp.status_mut() = local.status;
+                *resp.headers_mut() = local.headers;
+                (resp, "Map Local".to_string())
+            }
+            Err(e) => {
+                println!("map local error for rule {}: {}", rule.id, e);
+                let resp = Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(full(e))
+                    .unwrap();
+                (resp, "Map Local Failed".to_string())
+            }
         };
+        if let Some(origin) = &cors_origin {
+            cors::allow_origin(resp.headers_mut(), origin);
+        }
+        apply_header_rewrites(&ctx, Phase::Response, &rewrite_host, &request_path, resp.headers_mut());
+        return Ok(recorder.finish(&ctx.log_sender, status, String::new(), resp));
+    }
 
-        if let Some(addr) = addr {
-            let log_sender_clone = log_sender.clone();
-            let method_clone = method.clone();
-            let url_clone = url.clone();
-            
-            tokio::task::spawn(async move {
-                match hyper::upgrade::on(req).await {
-                    Ok(upgraded) => {
-                        if let Err(e) = tunnel(upgraded, addr).await {
-                            println!("server io error: {}", e);
-                        };
-                        // Log successful tunnel
-                        let _ = log_sender_clone.send(LogEvent {
-                            time: time::OffsetDateTime::now_utc().format(&time::format_description::parse("[hour]:[minute]:[second]").unwrap()).unwrap(),
-                            method: method_clone,
-                            protocol: "HTTPS".to_string(),
-                            url: url_clone,
-                            status: "Tunnel Established".to_string(),
-                            status_code: 200,
-                        }).await;
+    let addr = upstream.addr.clone();
+
+    if addr.is_empty() {
+        let resp = Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(full("Missing Host"))
+            .unwrap();
+        return Ok(recorder.finish(&ctx.log_sender, "Missing Host".to_string(), String::new(), resp));
+    }
+
+    // WebSocket upgrades are relayed frame by frame once both sides have switched
+    let client_upgrade = (req.version() == Version::HTTP_11 && websocket::is_upgrade(req.headers()))
+        .then(|| hyper::upgrade::on(&mut req));
+    if client_upgrade.is_some() {
+        // Compressed frames couldn't be shown or matched
+        req.headers_mut().remove(hyper::header::SEC_WEBSOCKET_EXTENSIONS);
+    } else if !req.headers().contains_key(hyper::header::UPGRADE) {
+        // Connection options only apply between the client and the proxy;
+        // upstream connections are kept alive for reuse
+        let listed: Vec<String> = req.headers().get_all(hyper::header::CONNECTION).iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|name| name.trim().to_ascii_lowercase())
+            .collect();
+        for name in listed.iter().map(String::as_str).chain(["connection", "proxy-connection", "keep-alive"]) {
+            req.headers_mut().remove(name);
+        }
+    }
+
+    let via = upstream_proxy(&ctx, &upstream.chain, &addr);
+    // Plain HTTP sent to an HTTP proxy is resolved by the proxy
+    if !sent_to_proxy(&upstream, via.as_ref())
+        && let Some(entry) = dns_override(&ctx, &addr)
+    {
+        recorder.dns_override = entry.describe(strip_brackets(target_host(&addr)));
+        upstream.resolved = Some(entry.address);
+    }
+    let allow_h2 = !req.headers().contains_key(hyper::header::UPGRADE);
+    let key = pool_key(&upstream, via.as_ref(), allow_h2);
+    let connect = || open_upstream(&upstream, via.as_ref(), allow_h2);
+    let mut conn = match ctx.pool.get(key, connect).await {
+        Ok(conn) => conn,
+        Err(e) => {
+            println!("connect error to {}: {}", addr, e);
+            let resp = Response::builder()
+                .status(StatusCode::BAD_GATEWAY)
+                .body(full(format!("Error connecting to upstream: {}", e)))
+                .unwrap();
+            return Ok(recorder.finish(&ctx.log_sender, "Upstream Unreachable".to_string(), String::new(), resp));
+        }
+    };
+
+    // Origin servers expect "/path?query" rather than the absolute proxy form
+    *req.uri_mut() = origin_form(req.uri(), upstream.path.as_deref());
+    let upstream_path = req.uri().clone();
+    // Plain HTTP through an HTTP proxy is sent to the proxy in absolute form
+    if let Some(proxy) = via.as_ref().filter(|_| sent_to_proxy(&upstream, via.as_ref())) {
+        if let Ok(uri) = format!("http://{}{}", addr, req.uri()).parse() {
+            *req.uri_mut() = uri;
+        }
+        if let Some(value) = proxy.authorization().and_then(|value| hyper::header::HeaderValue::from_str(&value).ok()) {
+            req.headers_mut().insert(hyper::header::PROXY_AUTHORIZATION, value);
+        }
+    }
+    if let Inbound::Terminated = inbound {
+        req.headers_mut().insert("x-forwarded-proto", hyper::header::HeaderValue::from_static("https"));
+    }
+    if let Some(host) = upstream.host.as_deref().and_then(|host| hyper::header::HeaderValue::from_str(host).ok()) {
+        req.headers_mut().insert(hyper::header::HOST, host);
+    }
+    apply_header_rewrites(&ctx, Phase::Request, &rewrite_host, &request_path, req.headers_mut());
+    recorder.set_request_headers(req.headers());
+    let scheme = if upstream.tls.is_some() { "https" } else { "http" };
+    // HTTP/2 carries the Host header as the URI's authority
+    if conn.sender.is_http2() {
+        let authority = req.headers_mut().remove(hyper::header::HOST)
+            .and_then(|host| host.to_str().ok().map(|host| host.to_string()))
+            .unwrap_or_else(|| addr.clone());
+        if let Ok(uri) = format!("{}://{}{}", scheme, authority, req.uri()).parse() {
+            *req.uri_mut() = uri;
+        }
+        *req.version_mut() = Version::HTTP_2;
+    }
+    let upstream_url = format!(
+        "{}://{}{}",
+        scheme,
+        upstream.host.as_deref().unwrap_or(&addr),
+        upstream_path
+    );
+    recorder.sending(conn.timings, conn.reused);
+    let sent = match conn.sender.try_send_request(req).await {
+        Ok(resp) => Ok(resp),
+        Err(mut e) => match e.take_message() {
+            // A kept-alive connection the upstream has meanwhile closed; the
+            // request never went out, so it can be sent on a new one
+            Some(req) if conn.reused => {
+                conn = match ctx.pool.reconnect(conn, connect).await {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        println!("connect error to {}: {}", addr, e);
+                        let resp = Response::builder()
+                            .status(StatusCode::BAD_GATEWAY)
+                            .body(full(format!("Error connecting to upstream: {}", e)))
+                            .unwrap();
+                        return Ok(recorder.finish(&ctx.log_sender, "Upstream Unreachable".to_string(), String::new(), resp));
                     }
-                    Err(e) => println!("upgrade error: {}", e),
+                };
+                recorder.sending(conn.timings, conn.reused);
+                conn.sender.try_send_request(req).await.map_err(|e| e.into_error())
+            }
+            _ => Err(e.into_error()),
+        },
+    };
+    let mut resp = match sent {
+        Ok(resp) => resp,
+        Err(e) => {
+            println!("request to {} failed: {}", upstream_url, e);
+            let resp = Response::builder()
+                .status(StatusCode::BAD_GATEWAY)
+                .body(full(format!("Error sending request to upstream: {}", e)))
+                .unwrap();
+            return Ok(recorder.finish(&ctx.log_sender, "Upstream Error".to_string(), upstream_url, resp));
+        }
+    };
+    ctx.pool.release(conn);
+    if let Some(client_upgrade) = client_upgrade
+        && resp.status() == StatusCode::SWITCHING_PROTOCOLS
+    {
+        let connection = ctx.websocket_hub.next_id();
+        recorder.websocket = Some(connection);
+        let server_upgrade = hyper::upgrade::on(&mut resp);
+        let drop = upstream.rule.as_ref().and_then(|rule| websocket::drop_pattern(&rule.ws_drop).ok().flatten());
+        let hub = ctx.websocket_hub.clone();
+        tokio::spawn(async move {
+            match tokio::try_join!(client_upgrade, server_upgrade) {
+                Ok((client, server)) => {
+                    websocket::relay(TokioIo::new(client), TokioIo::new(server), connection, drop, hub).await
                 }
-            });
+                Err(e) => println!("WebSocket upgrade failed: {}", e),
+            }
+        });
+    }
+    // Least-connections counts the request until its response body is done
+    let lease = upstream.lease.clone();
+    let mut resp = resp.map(|body| {
+        body.map_frame(move |frame| {
+            let _ = &lease;
+            frame
+        })
+        .boxed()
+    });
+    if let Some(origin) = &cors_origin {
+        cors::allow_origin(resp.headers_mut(), origin);
+    }
+    apply_header_rewrites(&ctx, Phase::Response, &rewrite_host, &request_path, resp.headers_mut());
+    if breakpoint_matches(&ctx, Phase::Response, &rewrite_host, &request_path) {
+        resp = pause_response(resp, &ctx, &recorder.method, &url).await?;
+    }
 
-            Ok(Response::new(empty()))
-        } else {
-            println!("CONNECT host is not socket addr: {:?}", req.uri());
-            let mut resp = Response::new(full("CONNECT must be to a socket address"));
-            *resp.status_mut() = StatusCode::BAD_REQUEST;
-            Ok(resp)
+    // Logged once the body has been passed on
+    let status = resp.status().to_string();
+    Ok(recorder.finish(&ctx.log_sender, status, upstream_url, resp))
+}
+
+// Proxy to reach `addr` through: the rule's own choice, or the global proxy
+// unless the host is in the bypass list
+fn upstream_proxy(ctx: &ProxyContext, chain: &ProxyChoice, addr: &str) -> Option<UpstreamProxy> {
+    match chain {
+        ProxyChoice::Direct => None,
+        ProxyChoice::Via(proxy) => Some(proxy.clone()),
+        ProxyChoice::Global => {
+            let settings = ctx.settings.read().ok()?;
+            let proxy = settings.upstream_proxy.as_ref()?;
+            (!settings.proxy_bypass.matches(target_host(addr))).then(|| proxy.clone())
         }
-    } else {
-        // Standard HTTP Proxy
-        let addr = if let Some(target) = target_addr {
-            target
-        } else {
-            // Determine upstream address
-            if let Some(host) = req.uri().host() {
-                let port = req.uri().port_u16().unwrap_or(80);
-                format!("{}:{}", host, port)
-            } else {
-                // Fallback to Host header
-                req.headers().get(hyper::header::HOST)
-                    .and_then(|h| h.to_str().ok())
-                    .map(|h| {
-                        if h.contains(':') {
-                            h.to_string()
-                        } else {
-                            format!("{}:80", h)
-                        }
-                    })
-                    .unwrap_or_else(|| String::new())
+    }
+}
+
+// Network profile for traffic matched by `rule`, falling back to the global one
+fn throttle_conditions(ctx: &ProxyContext, rule: Option<&Rule>) -> Option<Conditions> {
+    let settings = ctx.settings.read().ok()?;
+    let name = match rule.map(|rule| rule.throttle.as_str()) {
+        Some(name) if name != "global" && !name.is_empty() => name,
+        _ => settings.throttle_profile.as_str(),
+    };
+    throttle::profile(name, settings.custom_throttle)
+}
+
+// Apply `conditions` to the client connection: pace it, wait out the latency,
+// and maybe schedule a simulated drop
+// The link throttles the whole client connection, so on HTTP/2 the streams
+// share it and the last request to start sets its rate and drop for all of them
+async fn simulate_conditions(ctx: &ProxyContext, conditions: Option<Conditions>, url: &str) {
+    let drop_after = conditions.and_then(|conditions| conditions.roll_drop());
+    if let Some(bytes) = drop_after {
+        println!("Simulating a connection drop after {} bytes for {}", bytes, url);
+    }
+    ctx.link.set(conditions, drop_after);
+    if let Some(conditions) = conditions {
+        tokio::time::sleep(conditions.latency()).await;
+    }
+}
+
+// The request's Origin, when CORS injection is on and the matched rule accepts it
+fn cors_origin(ctx: &ProxyContext, upstream: &Upstream, headers: &hyper::HeaderMap) -> Option<cors::Grant> {
+    let enabled = ctx.settings.read().map(|s| s.cors_enabled).unwrap_or(false);
+    let origin = headers.get(hyper::header::ORIGIN)?;
+    let allowed = upstream.rule.as_ref().map(|rule| rule.cors_origins.as_str()).unwrap_or_default();
+    cors::grant(allowed, origin).filter(|_| enabled)
+}
+
+fn breakpoint_matches(ctx: &ProxyContext, phase: Phase, host: &str, path: &str) -> bool {
+    ctx.breakpoints
+        .read()
+        .map(|breakpoints| breakpoint::matches(&breakpoints, phase, host, path))
+        .unwrap_or(false)
+}
+
+// Hold a request for the user. Ok(request) carries on with the (edited)
+// request, Err(response) answers the client without contacting the upstream.
+async fn pause_request(
+    req: Request<BoxBody<Bytes, hyper::Error>>,
+    ctx: &ProxyContext,
+    inbound: &Inbound,
+    recorder: &mut Recorder,
+) -> Result<Result<Request<BoxBody<Bytes, hyper::Error>>, Response<BoxBody<Bytes, hyper::Error>>>, hyper::Error> {
+    let (url, protocol, _) = describe(&req, inbound);
+    let (mut parts, body) = req.into_parts();
+    let body = match breakpoint::read_body(body).await? {
+        Ok(body) => body,
+        Err(body) => {
+            println!("Not pausing request to {}: body over {} bytes", url, breakpoint::MAX_PAUSED_BODY);
+            return Ok(Ok(Request::from_parts(parts, body)));
+        }
+    };
+
+    let message = PausedMessage::new(Phase::Request, parts.method.to_string(), url.clone(), 0, &parts.headers, &body);
+    let binary = message.binary;
+    let Some(decision) = ctx.breakpoint_hub.pause(message).await else {
+        return Ok(Ok(Request::from_parts(parts, full(body))));
+    };
+
+    let (resp, status) = match decision.resolution {
+        Resolution::Continue => {
+            match Method::from_bytes(decision.method.trim().as_bytes()) {
+                Ok(method) => parts.method = method,
+                Err(_) => println!("Ignoring edited method: {}", decision.method),
             }
-        };
+            parts.headers = decision.header_map(&parts.headers);
+            if decision.url.trim() != url {
+                apply_edited_url(&mut parts, inbound, decision.url.trim());
+            }
+            let body = decision.body_bytes(body, binary);
+            return Ok(Ok(Request::from_parts(parts, full(body))));
+        }
+        Resolution::Abort => {
+            let resp = Response::builder()
+                .status(StatusCode::BAD_GATEWAY)
+                .body(full("Request aborted at breakpoint"))
+                .unwrap();
+            (resp, "Breakpoint Aborted")
+        }
+        Resolution::Respond => (canned_response(&decision), "Breakpoint Response"),
+    };
+
+    recorder.method = parts.method.to_string();
+    recorder.url = url;
+    recorder.protocol = protocol;
+    recorder.set_request_headers(&parts.headers);
+    recorder.set_request_body(&body);
+    Ok(Err(recorder.finish(&ctx.log_sender, status.to_string(), String::new(), resp)))
+}
 
-        if addr.is_empty() {
-             return Ok(Response::builder()
-                    .status(StatusCode::BAD_REQUEST)
-                    .body(full("Missing Host"))
-                    .unwrap());
+// Point the request at an edited URL; a new host also replaces the Host header
+fn apply_edited_url(parts: &mut hyper::http::request::Parts, inbound: &Inbound, edited: &str) {
+    let uri: hyper::Uri = match edited.parse() {
+        Ok(uri) => uri,
+        Err(e) => {
+            println!("Ignoring edited URL {}: {}", edited, e);
+            return;
         }
+    };
 
-        let stream = match TcpStream::connect(&addr).await {
-            Ok(stream) => stream,
-            Err(e) => {
-                println!("connect error to {}: {}", addr, e);
-                return Ok(Response::builder()
-                    .status(StatusCode::BAD_GATEWAY)
-                    .body(full(format!("Error connecting to upstream: {}", e)))
-                    .unwrap());
+    if let Some(authority) = uri.authority()
+        && let Ok(host) = hyper::header::HeaderValue::from_str(authority.as_str())
+    {
+        parts.headers.insert(hyper::header::HOST, host);
+    }
+    parts.uri = match inbound {
+        Inbound::Plain if uri.authority().is_some() => uri,
+        _ => origin_form(&uri, None),
+    };
+}
+
+async fn pause_response(
+    resp: Response<BoxBody<Bytes, hyper::Error>>,
+    ctx: &ProxyContext,
+    method: &str,
+    url: &str,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+    let (mut parts, body) = resp.into_parts();
+    let body = match breakpoint::read_body(body).await? {
+        Ok(body) => body,
+        Err(body) => {
+            println!("Not pausing response from {}: body over {} bytes", url, breakpoint::MAX_PAUSED_BODY);
+            return Ok(Response::from_parts(parts, body));
+        }
+    };
+
+    let message = PausedMessage::new(
+        Phase::Response,
+        method.to_string(),
+        url.to_string(),
+        parts.status.as_u16(),
+        &parts.headers,
+        &body,
+    );
+    let binary = message.binary;
+    let Some(decision) = ctx.breakpoint_hub.pause(message).await else {
+        return Ok(Response::from_parts(parts, full(body)));
+    };
+
+    match decision.resolution {
+        Resolution::Continue | Resolution::Respond => {
+            parts.status = StatusCode::from_u16(decision.status).unwrap_or(parts.status);
+            parts.headers = decision.header_map(&parts.headers);
+            let body = decision.body_bytes(body, binary);
+            Ok(Response::from_parts(parts, full(body)))
+        }
+        Resolution::Abort => Ok(Response::builder()
+            .status(StatusCode::BAD_GATEWAY)
+            .body(full("Response aborted at breakpoint"))
+            .unwrap()),
+    }
+}
+
+// Response typed in at a request breakpoint
+fn canned_response(decision: &Decision) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let mut resp = Response::new(full(decision.body.clone()));
+    *resp.status_mut() = StatusCode::from_u16(decision.status).unwrap_or(StatusCode::OK);
+    *resp.headers_mut() = decision.header_map(&hyper::HeaderMap::new());
+    resp
+}
+
+fn apply_header_rewrites(ctx: &ProxyContext, phase: Phase, host: &str, path: &str, headers: &mut hyper::HeaderMap) {
+    if let Ok(rewrites) = ctx.header_rewrites.read() {
+        headers::apply(&rewrites, phase, host, path, headers);
+    }
+}
+
+// Pick the enabled rule for `host` and `path` by the precedence documented in
+// `matcher`. Without a path (CONNECT tunnels) only path-less rules apply.
+fn find_rule<'a>(rules: &'a [CompiledRule], host: &str, path: Option<&str>) -> Option<&'a CompiledRule> {
+    rules
+        .iter()
+        .filter(|compiled| compiled.rule.enabled && compiled.pattern.matches(host))
+        .filter(|compiled| match path {
+            Some(path) => compiled.path.matches(path),
+            None => matches!(compiled.path, PathPattern::Any),
+        })
+        .min_by_key(|compiled| (compiled.pattern.rank(), compiled.path.rank()))
+}
+
+fn match_rule(rules: &RwLock<Vec<CompiledRule>>, host: &str, path: Option<&str>) -> Option<Upstream> {
+    let rules = rules.read().ok()?;
+    let compiled = find_rule(&rules, host, path)?;
+    if let Some(remote) = &compiled.remote {
+        return Some(remote_upstream(compiled, remote, path));
+    }
+
+    let rule = &compiled.rule;
+    let lease = compiled.balancer.as_ref().and_then(|balancer| balancer.pick());
+    let target = lease.as_ref().map(Lease::target).unwrap_or(rule.target.trim());
+    println!("Matched rule {}: {} -> {}", rule.id, rule.domain, target);
+
+    let mut upstream = forward_upstream(compiled, target, path);
+    upstream.lease = lease.map(Arc::new);
+    Some(upstream)
+}
+
+// Forwarding rules send requests to `target`, one of the rule's targets
+fn forward_upstream(compiled: &CompiledRule, target: &str, path: Option<&str>) -> Upstream {
+    let rule = &compiled.rule;
+    let https = rule.protocol.eq_ignore_ascii_case("HTTPS");
+    let h2c = rule.protocol.eq_ignore_ascii_case("H2C");
+    let default_port = if https { 443 } else { 80 };
+
+    let target = target.to_string();
+    let addr = if !target.is_empty() && target.chars().all(|c| c.is_numeric()) {
+        format!("127.0.0.1:{}", target)
+    } else if (!target.is_empty() && !target.contains(':')) || (target.starts_with('[') && target.ends_with(']')) {
+        format!("{}:{}", target, default_port)
+    } else {
+        target
+    };
+
+    let tls = https.then(|| UpstreamTls {
+        server_name: strip_brackets(target_host(&addr)).to_string(),
+        verify: !rule.skip_verify,
+    });
+
+    let path = path
+        .filter(|_| rule.rewrite_path)
+        .map(|path| compiled.path.rewrite(path, &rule.path_rewrite));
+
+    Upstream {
+        addr,
+        tls,
+        path,
+        host: None,
+        h2c,
+        rule: Some(rule.clone()),
+        chain: compiled.chain.clone(),
+        lease: None,
+        resolved: None,
+    }
+}
+
+// Map-remote rules replace scheme, host, port and the matched part of the path
+fn remote_upstream(compiled: &CompiledRule, remote: &RemoteTarget, path: Option<&str>) -> Upstream {
+    let rule = &compiled.rule;
+    println!("Matched rule {}: {} -> {}", rule.id, rule.domain, rule.remote_url);
+
+    Upstream {
+        addr: remote.addr(),
+        tls: remote.https.then(|| UpstreamTls {
+            server_name: strip_brackets(&remote.host).to_string(),
+            verify: !rule.skip_verify,
+        }),
+        path: path.map(|path| remote.map_path(&compiled.path, path)),
+        host: (!rule.keep_host).then(|| remote.authority()),
+        h2c: false,
+        rule: Some(rule.clone()),
+        chain: compiled.chain.clone(),
+        lease: None,
+        resolved: None,
+    }
+}
+
+// The balancer for a forwarding rule. It is kept across rule updates while
+// the rule's targets and health checks stay the same, so its health state
+// and running check carry over.
+fn balancer_for(rule: &Rule, previous: &[(Rule, Arc<Balancer>)]) -> Result<Arc<Balancer>, String> {
+    let strategy = Strategy::parse(&rule.balance)?;
+    let targets = balance::parse_targets(&rule.target)?;
+    let unchanged = previous.iter().find(|(old, _)| {
+        old.id == rule.id
+            && old.target == rule.target
+            && old.balance == rule.balance
+            && old.health_path == rule.health_path
+            && old.health_interval == rule.health_interval
+    });
+    if let Some((_, balancer)) = unchanged {
+        return Ok(balancer.clone());
+    }
+
+    Ok(Arc::new(Balancer::new(targets, strategy)))
+}
+
+// Probe a rule's targets every `health_interval` seconds, taking the ones
+// that fail out of rotation. Ends once the rule no longer uses `balancer`,
+// or when `sync_health_checks` stops it.
+async fn health_checks(ctx: ProxyContext, balancer: Weak<Balancer>, sender: mpsc::Sender<HealthEvent>) {
+    loop {
+        let Some(balancer) = balancer.upgrade() else {
+            return;
+        };
+        let compiled = ctx.rules.read().ok().and_then(|rules| {
+            rules
+                .iter()
+                .find(|compiled| compiled.balancer.as_ref().is_some_and(|other| Arc::ptr_eq(other, &balancer)))
+                .cloned()
+        });
+        let Some(compiled) = compiled else {
+            return;
+        };
+
+        if compiled.rule.enabled {
+            for backend in balancer.backends() {
+                let upstream = forward_upstream(&compiled, &backend.target, None);
+                let up = probe(&ctx, &upstream, &compiled.rule.health_path).await;
+                if backend.set_up(up) {
+                    println!("Health check of rule {}: {} is {}", compiled.rule.id, backend.target, if up { "up" } else { "down" });
+                    let _ = sender.send(HealthEvent {
+                        rule_id: compiled.rule.id.clone(),
+                        target: backend.target.clone(),
+                        up,
+                    }).await;
+                }
             }
+        }
+
+        let interval = compiled.rule.health_interval.max(1) as u64;
+        drop(balancer);
+        tokio::time::sleep(std::time::Duration::from_secs(interval)).await;
+    }
+}
+
+// Whether `upstream` answers a GET of `path` with a 2xx or 3xx status in time
+async fn probe(ctx: &ProxyContext, upstream: &Upstream, path: &str) -> bool {
+    let path = format!("/{}", path.trim().trim_start_matches('/'));
+    let via = upstream_proxy(ctx, &upstream.chain, &upstream.addr);
+    let mut upstream = upstream.clone();
+    if !sent_to_proxy(&upstream, via.as_ref()) {
+        upstream.resolved = dns_override(ctx, &upstream.addr).map(|entry| entry.address);
+    }
+    let upstream = &upstream;
+    let check = async {
+        let (mut sender, _) = open_upstream(upstream, via.as_ref(), true).await?;
+        let scheme = if upstream.tls.is_some() { "https" } else { "http" };
+        let to_proxy = sent_to_proxy(upstream, via.as_ref());
+        let uri = if sender.is_http2() || to_proxy {
+            format!("{}://{}{}", scheme, upstream.addr, path)
+        } else {
+            path.clone()
         };
 
-        let io = TokioIo::new(stream);
-        let (mut sender, conn) = hyper::client::conn::http1::handshake(io).await?;
+        let mut req = Request::get(uri);
+        if sender.is_http2() {
+            req = req.version(Version::HTTP_2);
+        } else {
+            req = req.header(hyper::header::HOST, upstream.addr.as_str());
+        }
+        if let Some(authorization) = via.as_ref().filter(|_| to_proxy).and_then(|proxy| proxy.authorization()) {
+            req = req.header(hyper::header::PROXY_AUTHORIZATION, authorization);
+        }
+        let resp = sender.try_send_request(req.body(empty())?).await.map_err(|e| e.into_error())?;
+        Ok::<_, Box<dyn std::error::Error + Send + Sync>>(resp.status())
+    };
+
+    match tokio::time::timeout(balance::HEALTH_TIMEOUT, check).await {
+        Ok(Ok(status)) => status.is_success() || status.is_redirection(),
+        _ => false,
+    }
+}
+
+// Host part of a host:port address
+fn target_host(addr: &str) -> &str {
+    match addr.rsplit_once(':') {
+        Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+        _ => addr,
+    }
+}
+
+// Pooled connections are shared between requests that would open the same one
+fn pool_key(upstream: &Upstream, via: Option<&UpstreamProxy>, allow_h2: bool) -> PoolKey {
+    PoolKey {
+        addr: upstream.addr.clone(),
+        tls: upstream.tls.as_ref().map(|tls| (tls.server_name.clone(), tls.verify)),
+        h2c: upstream.h2c,
+        allow_h2,
+        via: via.cloned(),
+        resolved: upstream.resolved,
+    }
+}
+
+// Plain HTTP through an HTTP proxy is sent to the proxy itself; everything
+// else is tunneled through it
+fn sent_to_proxy(upstream: &Upstream, via: Option<&UpstreamProxy>) -> bool {
+    via.is_some_and(|proxy| proxy.kind == ProxyKind::Http) && upstream.tls.is_none() && !upstream.h2c
+}
+
+// Connect to the upstream, directly or through `via`, and perform the
+// handshake, optionally over TLS. HTTP/2 is used when the upstream picks it
+// via ALPN, or for h2c rules; `allow_h2` is false for requests that need an
+// HTTP/1 upgrade.
+async fn open_upstream(
+    upstream: &Upstream,
+    via: Option<&UpstreamProxy>,
+    allow_h2: bool,
+) -> Result<(UpstreamSender, ConnectTimings), Box<dyn std::error::Error + Send + Sync>> {
+    let mut timings = ConnectTimings::default();
+    let stream = match via {
+        Some(proxy) if sent_to_proxy(upstream, via) => connect_direct(&proxy.addr(), &mut timings).await?,
+        Some(proxy) => {
+            let started = Instant::now();
+            let stream = proxy.connect(&dial_addr(upstream)).await?;
+            timings.connect = Some(started.elapsed());
+            stream
+        }
+        None => connect_direct(&dial_addr(upstream), &mut timings).await?,
+    };
+
+    match &upstream.tls {
+        Some(tls) => {
+            let started = Instant::now();
+            let server_name = ServerName::try_from(tls.server_name.clone())?;
+            let stream = TlsConnector::from(tls::client_config(tls.verify, allow_h2))
+                .connect(server_name, stream)
+                .await?;
+            timings.tls = Some(started.elapsed());
+            let h2 = stream.get_ref().1.alpn_protocol() == Some(b"h2");
+            Ok((handshake(stream, h2).await?, timings))
+        }
+        None => Ok((handshake(stream, upstream.h2c && allow_h2).await?, timings)),
+    }
+}
+
+// Where to connect for `upstream`: its address, or the overriding IP on the same port
+fn dial_addr(upstream: &Upstream) -> String {
+    match upstream.resolved {
+        Some(ip) => with_ip(&upstream.addr, ip),
+        None => upstream.addr.clone(),
+    }
+}
+
+fn with_ip(addr: &str, ip: IpAddr) -> String {
+    let port = addr.rsplit_once(':').and_then(|(_, port)| port.parse().ok()).unwrap_or(80);
+    SocketAddr::new(ip, port).to_string()
+}
+
+// The enabled DNS override for the host of `addr`, if any
+fn dns_override(ctx: &ProxyContext, addr: &str) -> Option<CompiledDnsOverride> {
+    let overrides = ctx.dns_overrides.read().ok()?;
+    dns::lookup(&overrides, strip_brackets(target_host(addr))).cloned()
+}
+
+// Resolve `addr` and connect to it, timing each step
+async fn connect_direct(addr: &str, timings: &mut ConnectTimings) -> std::io::Result<TcpStream> {
+    let started = Instant::now();
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host(addr).await?.collect();
+    timings.dns = Some(started.elapsed());
+    let started = Instant::now();
+    let stream = TcpStream::connect(&addrs[..]).await?;
+    timings.connect = Some(started.elapsed());
+    Ok(stream)
+}
+
+async fn handshake<I>(io: I, h2: bool) -> Result<UpstreamSender, hyper::Error>
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    if h2 {
+        let (sender, conn) = http2::handshake(TokioExecutor::new(), TokioIo::new(io)).await?;
         tokio::task::spawn(async move {
             if let Err(err) = conn.await {
                 println!("Connection failed: {:?}", err);
             }
         });
+        return Ok(UpstreamSender::Http2(sender));
+    }
 
-        let resp = sender.send_request(req).await?;
-        
-        // Log response
-        let _ = log_sender.send(LogEvent {
-            time: time::OffsetDateTime::now_utc().format(&time::format_description::parse("[hour]:[minute]:[second]").unwrap()).unwrap(),
-            method,
-            protocol,
-            url,
-            status: resp.status().to_string(),
-            status_code: resp.status().as_u16() as i32,
-        }).await;
+    let (sender, conn) = http1::handshake(TokioIo::new(io)).await?;
+    tokio::task::spawn(async move {
+        if let Err(err) = conn.with_upgrades().await {
+            println!("Connection failed: {:?}", err);
+        }
+    });
+    Ok(UpstreamSender::Http1(sender))
+}
 
-        Ok(resp.map(|b| b.boxed()))
-    }
+// Origin-form target for `uri`, optionally with its path replaced
+fn origin_form(uri: &hyper::Uri, path: Option<&str>) -> hyper::Uri {
+    let path = path.unwrap_or(uri.path());
+    let target = match uri.query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_string(),
+    };
+    target.parse().unwrap_or_else(|_| hyper::Uri::from_static("/"))
 }
 
 fn host_addr(uri: &hyper::Uri) -> Option<String> {
     uri.authority().map(|auth| auth.to_string())
 }
 
+// Authority as it appears in an https:// URL, without the default port
+fn display_authority(authority: &Authority) -> String {
+    match authority.port_u16() {
+        Some(443) | None => authority.host().to_string(),
+        Some(_) => authority.to_string(),
+    }
+}
+
+pub fn log_time() -> String {
+    time::OffsetDateTime::now_utc()
+        .format(time::macros::format_description!("[hour]:[minute]:[second]"))
+        .unwrap_or_default()
+}
+
 fn empty() -> BoxBody<Bytes, hyper::Error> {
     Empty::<Bytes>::new()
         .map_err(|never| match never {})
@@ -325,22 +2059,43 @@ fn full<T: Into<Bytes>>(chunk: T) -> BoxBody<Bytes, hyper::Error> {
     .boxed()
 }
 
-// Create a TCP connection to host:port, build a tunnel between the connection and
-// the upgraded connection
-async fn tunnel(
-    upgraded: hyper::upgrade::Upgraded,
-    addr: String,
-) -> std::io::Result<()> {
-    let mut server = TcpStream::connect(addr).await?;
-    let mut upgraded = TokioIo::new(upgraded);
+// Terminate TLS from the client with a leaf certificate issued by the Ovo CA and
+// serve the decrypted requests through the regular forwarding path
+async fn intercept_tunnel<I>(
+    io: I,
+    authority: Authority,
+    ctx: ProxyContext,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let host = strip_brackets(authority.host()).to_string();
+    let acceptor = TlsAcceptor::from(tls::server_config(ctx.ca.clone(), Some(host)));
+    let stream = acceptor.accept(io).await?;
+
+    http_server()
+        .serve_connection_with_upgrades(
+            TokioIo::new(stream),
+            service_fn(move |req| forward(req, ctx.clone(), Inbound::Intercepted(authority.clone()))),
+        )
+        .await?;
+
+    Ok(())
+}
 
+// Build a tunnel between the client's connection and the server's. Returns the
+// bytes sent by the client and by the server.
+async fn tunnel<I>(mut client: I, mut server: TcpStream) -> std::io::Result<(u64, u64)>
+where
+    I: AsyncRead + AsyncWrite + Unpin,
+{
     let (from_client, from_server) =
-        tokio::io::copy_bidirectional(&mut upgraded, &mut server).await?;
+        tokio::io::copy_bidirectional(&mut client, &mut server).await?;
 
     println!(
         "client wrote {} bytes and received {} bytes",
         from_client, from_server
     );
 
-    Ok(())
+    Ok((from_client, from_server))
 }
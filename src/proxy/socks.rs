@@ -0,0 +1,229 @@
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::auth::Credentials;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Version {
+    Socks4,
+    Socks5,
+}
+
+impl Version {
+    pub fn name(self) -> &'static str {
+        match self {
+            Version::Socks4 => "SOCKS4",
+            Version::Socks5 => "SOCKS5",
+        }
+    }
+}
+
+// What a client is told about its CONNECT request
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reply {
+    Succeeded,
+    Failed,
+    HostUnreachable,
+    ConnectionRefused,
+}
+
+impl Reply {
+    // The reply for a destination that couldn't be connected to
+    pub fn for_error(error: &io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::ConnectionRefused => Reply::ConnectionRefused,
+            _ => Reply::HostUnreachable,
+        }
+    }
+}
+
+// Destination a SOCKS client asked to CONNECT to
+#[derive(Clone, Debug)]
+pub struct SocksRequest {
+    pub version: Version,
+    pub host: String,
+    pub port: u16,
+}
+
+impl SocksRequest {
+    // host:port, with IPv6 hosts in brackets
+    pub fn addr(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+// Read a SOCKS5 or SOCKS4/4a CONNECT request. Only CONNECT is supported;
+// anything else is refused before returning. With `credentials`, SOCKS5
+// clients must log in with them and SOCKS4 clients, which can't, are refused
+// with a `PermissionDenied` error.
+pub async fn accept<S>(stream: &mut S, credentials: Option<&Credentials>) -> io::Result<SocksRequest>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match stream.read_u8().await? {
+        5 => accept_socks5(stream, credentials).await,
+        4 if credentials.is_some() => {
+            accept_socks4(stream).await?;
+            reply(stream, Version::Socks4, Reply::Failed).await?;
+            Err(io::Error::new(io::ErrorKind::PermissionDenied, "SOCKS4 clients can't authenticate"))
+        }
+        4 => accept_socks4(stream).await,
+        version => Err(io::Error::other(format!("unsupported SOCKS version {}", version))),
+    }
+}
+
+// Tell the client whether its CONNECT succeeded. SOCKS4 has a single code
+// for every failure.
+pub async fn reply<S>(stream: &mut S, version: Version, reply: Reply) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    // The bound address is not meaningful here, so it is always zero
+    let reply: &[u8] = match (version, reply) {
+        (Version::Socks5, Reply::Succeeded) => &[5, 0, 0, 1, 0, 0, 0, 0, 0, 0],
+        (Version::Socks5, Reply::Failed) => &[5, 1, 0, 1, 0, 0, 0, 0, 0, 0],
+        (Version::Socks5, Reply::HostUnreachable) => &[5, 4, 0, 1, 0, 0, 0, 0, 0, 0],
+        (Version::Socks5, Reply::ConnectionRefused) => &[5, 5, 0, 1, 0, 0, 0, 0, 0, 0],
+        (Version::Socks4, Reply::Succeeded) => &[0, 0x5a, 0, 0, 0, 0, 0, 0],
+        (Version::Socks4, _) => &[0, 0x5b, 0, 0, 0, 0, 0, 0],
+    };
+    stream.write_all(reply).await?;
+    stream.flush().await
+}
+
+async fn accept_socks5<S>(stream: &mut S, credentials: Option<&Credentials>) -> io::Result<SocksRequest>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let count = stream.read_u8().await?;
+    let mut methods = vec![0u8; count as usize];
+    stream.read_exact(&mut methods).await?;
+    match credentials {
+        Some(credentials) if methods.contains(&2) => {
+            stream.write_all(&[5, 2]).await?;
+            login(stream, credentials).await?;
+        }
+        Some(_) => {
+            stream.write_all(&[5, 0xff]).await?;
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "SOCKS5 client doesn't offer username/password authentication",
+            ));
+        }
+        None if methods.contains(&0) => stream.write_all(&[5, 0]).await?,
+        None => {
+            stream.write_all(&[5, 0xff]).await?;
+            return Err(io::Error::other("SOCKS5 client requires authentication"));
+        }
+    }
+
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+    let host = match header[3] {
+        1 => {
+            let mut ip = [0u8; 4];
+            stream.read_exact(&mut ip).await?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        3 => {
+            let len = stream.read_u8().await?;
+            let mut name = vec![0u8; len as usize];
+            stream.read_exact(&mut name).await?;
+            String::from_utf8(name).map_err(|_| io::Error::other("SOCKS5 host name is not UTF-8"))?
+        }
+        4 => {
+            let mut ip = [0u8; 16];
+            stream.read_exact(&mut ip).await?;
+            Ipv6Addr::from(ip).to_string()
+        }
+        _ => {
+            stream.write_all(&[5, 8, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+            return Err(io::Error::other("SOCKS5 address type not supported"));
+        }
+    };
+    let port = stream.read_u16().await?;
+
+    if header[1] != 1 {
+        stream.write_all(&[5, 7, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+        return Err(io::Error::other(format!("SOCKS5 command {} not supported", header[1])));
+    }
+
+    Ok(SocksRequest {
+        version: Version::Socks5,
+        host,
+        port,
+    })
+}
+
+// Username/password subnegotiation (RFC 1929)
+async fn login<S>(stream: &mut S, credentials: &Credentials) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let _version = stream.read_u8().await?;
+    let mut username = vec![0u8; stream.read_u8().await? as usize];
+    stream.read_exact(&mut username).await?;
+    let mut password = vec![0u8; stream.read_u8().await? as usize];
+    stream.read_exact(&mut password).await?;
+
+    if credentials.matches(&username, &password) {
+        stream.write_all(&[1, 0]).await?;
+        return Ok(());
+    }
+    stream.write_all(&[1, 1]).await?;
+    Err(io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!("wrong username or password for user {:?}", String::from_utf8_lossy(&username)),
+    ))
+}
+
+async fn accept_socks4<S>(stream: &mut S) -> io::Result<SocksRequest>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let command = stream.read_u8().await?;
+    let port = stream.read_u16().await?;
+    let mut ip = [0u8; 4];
+    stream.read_exact(&mut ip).await?;
+    // The user id is ignored
+    read_nul_terminated(stream).await?;
+
+    // SOCKS4a: an address of 0.0.0.x (x != 0) means a host name follows
+    let host = if ip[..3] == [0, 0, 0] && ip[3] != 0 {
+        String::from_utf8(read_nul_terminated(stream).await?)
+            .map_err(|_| io::Error::other("SOCKS4a host name is not UTF-8"))?
+    } else {
+        Ipv4Addr::from(ip).to_string()
+    };
+
+    if command != 1 {
+        reply(stream, Version::Socks4, Reply::Failed).await?;
+        return Err(io::Error::other(format!("SOCKS4 command {} not supported", command)));
+    }
+
+    Ok(SocksRequest {
+        version: Version::Socks4,
+        host,
+        port,
+    })
+}
+
+async fn read_nul_terminated<S>(stream: &mut S) -> io::Result<Vec<u8>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut bytes = Vec::new();
+    loop {
+        match stream.read_u8().await? {
+            0 => return Ok(bytes),
+            _ if bytes.len() >= 255 => return Err(io::Error::other("SOCKS4 field too long")),
+            byte => bytes.push(byte),
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use rand::Rng;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+
+// Simulated network link. Bandwidths are in kbit/s, 0 meaning unlimited.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Conditions {
+    pub down_kbps: u32,
+    pub up_kbps: u32,
+    pub latency_ms: u32,
+    // Chance, in percent, that a request or tunnel has its connection dropped
+    pub drop_percent: u32,
+}
+
+impl Conditions {
+    pub fn latency(&self) -> Duration {
+        Duration::from_millis(self.latency_ms as u64)
+    }
+
+    // Roll for a dropped connection; Some(n) cuts it after n more bytes to the client
+    pub fn roll_drop(&self) -> Option<u64> {
+        let mut rng = rand::rng();
+        (self.drop_percent > 0 && rng.random_range(0..100) < self.drop_percent).then(|| rng.random_range(0..16 * 1024))
+    }
+}
+
+// Built-in profiles, by the names used in settings and rules. "custom" takes
+// the values configured in settings, "off" (or anything unknown) disables
+// conditioning.
+pub fn profile(name: &str, custom: Conditions) -> Option<Conditions> {
+    let conditions = match name {
+        "slow-3g" => Conditions {
+            down_kbps: 400,
+            up_kbps: 400,
+            latency_ms: 2000,
+            drop_percent: 0,
+        },
+        "3g" => Conditions {
+            down_kbps: 1600,
+            up_kbps: 750,
+            latency_ms: 560,
+            drop_percent: 0,
+        },
+        "slow-4g" => Conditions {
+            down_kbps: 4000,
+            up_kbps: 3000,
+            latency_ms: 150,
+            drop_percent: 0,
+        },
+        "custom" => custom,
+        _ => return None,
+    };
+    Some(conditions)
+}
+
+// Conditions for one client connection. Requests on a keep-alive connection
+// replace them as they match different rules.
+#[derive(Debug, Default)]
+pub struct Link {
+    state: Mutex<LinkState>,
+}
+
+#[derive(Debug, Default)]
+struct LinkState {
+    conditions: Option<Conditions>,
+    // Bytes left before the connection is cut
+    drop_after: Option<u64>,
+}
+
+impl Link {
+    pub fn set(&self, conditions: Option<Conditions>, drop_after: Option<u64>) {
+        if let Ok(mut state) = self.state.lock() {
+            state.conditions = conditions;
+            state.drop_after = drop_after;
+        }
+    }
+
+    fn conditions(&self) -> Option<Conditions> {
+        self.state.lock().ok().and_then(|state| state.conditions)
+    }
+
+    // Whether `len` more bytes may be written; false once the link is cut
+    fn allows(&self, len: usize) -> bool {
+        let Ok(mut state) = self.state.lock() else {
+            return true;
+        };
+        match state.drop_after {
+            Some(left) if left < len as u64 => {
+                state.drop_after = Some(0);
+                false
+            }
+            _ => true,
+        }
+    }
+
+    // Account for `len` bytes actually written
+    fn consume(&self, len: usize) {
+        if let Ok(mut state) = self.state.lock()
+            && let Some(left) = state.drop_after.as_mut()
+        {
+            *left = left.saturating_sub(len as u64);
+        }
+    }
+}
+
+// Client stream paced by the connection's `Link`: writes to the client use
+// the download rate, reads from it the upload rate
+pub struct ThrottledStream<S> {
+    inner: S,
+    link: std::sync::Arc<Link>,
+    read_pause: Option<Pin<Box<Sleep>>>,
+    write_pause: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> ThrottledStream<S> {
+    pub fn new(inner: S, link: std::sync::Arc<Link>) -> Self {
+        Self {
+            inner,
+            link,
+            read_pause: None,
+            write_pause: None,
+        }
+    }
+}
+
+fn bytes_per_sec(kbps: u32) -> f64 {
+    kbps as f64 * 1000.0 / 8.0
+}
+
+// At most ~100ms worth of data per call, so pacing stays smooth
+fn chunk_len(len: usize, kbps: u32) -> usize {
+    len.min((bytes_per_sec(kbps) / 10.0).max(1.0) as usize)
+}
+
+fn pause_for(len: usize, kbps: u32) -> Pin<Box<Sleep>> {
+    Box::pin(tokio::time::sleep(Duration::from_secs_f64(len as f64 / bytes_per_sec(kbps))))
+}
+
+fn poll_pause(pause: &mut Option<Pin<Box<Sleep>>>, cx: &mut Context<'_>) -> Poll<()> {
+    if let Some(sleep) = pause {
+        ready!(sleep.as_mut().poll(cx));
+        *pause = None;
+    }
+    Poll::Ready(())
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ThrottledStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        ready!(poll_pause(&mut this.read_pause, cx));
+
+        let up_kbps = this.link.conditions().map(|conditions| conditions.up_kbps).unwrap_or(0);
+        if up_kbps == 0 {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+
+        let limit = chunk_len(buf.remaining(), up_kbps);
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(limit));
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+        let read = limited.filled().len();
+        buf.advance(read);
+
+        if read > 0 {
+            this.read_pause = Some(pause_for(read, up_kbps));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ThrottledStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        ready!(poll_pause(&mut this.write_pause, cx));
+
+        let down_kbps = this.link.conditions().map(|conditions| conditions.down_kbps).unwrap_or(0);
+        let len = if down_kbps == 0 { buf.len() } else { chunk_len(buf.len(), down_kbps) };
+        if !this.link.allows(len) {
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::ConnectionReset, "simulated connection drop")));
+        }
+
+        // Only bytes that went out count, not retries of a pending or failed write
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..len]))?;
+        this.link.consume(written);
+        if down_kbps > 0 && written > 0 {
+            this.write_pause = Some(pause_for(written, down_kbps));
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,156 @@
+use std::fmt;
+use std::sync::{Arc, OnceLock};
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme};
+
+use super::ca::CertificateAuthority;
+
+type HostFilter = Box<dyn Fn(&str) -> bool + Send + Sync>;
+
+// Picks the leaf certificate by SNI, falling back to the CONNECT host for
+// clients that don't send one (e.g. when connecting to a bare IP)
+pub struct LeafCertResolver {
+    ca: Arc<CertificateAuthority>,
+    fallback_host: Option<String>,
+    allow: Option<HostFilter>,
+}
+
+impl fmt::Debug for LeafCertResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LeafCertResolver")
+            .field("fallback_host", &self.fallback_host)
+            .finish()
+    }
+}
+
+impl ResolvesServerCert for LeafCertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let host = client_hello
+            .server_name()
+            .map(|name| name.to_string())
+            .or_else(|| self.fallback_host.clone())?;
+
+        if let Some(allow) = &self.allow
+            && !allow(&host)
+        {
+            println!("Not issuing certificate for {}", host);
+            return None;
+        }
+
+        match self.ca.leaf_cert(&host) {
+            Ok(key) => Some(key),
+            Err(e) => {
+                println!("Failed to issue certificate for {}: {}", host, e);
+                None
+            }
+        }
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn build_server_config(resolver: LeafCertResolver) -> Arc<ServerConfig> {
+    let mut config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .expect("ring provider supports the default protocol versions")
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(resolver));
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Arc::new(config)
+}
+
+// Config for an intercepted CONNECT tunnel to `fallback_host`
+pub fn server_config(ca: Arc<CertificateAuthority>, fallback_host: Option<String>) -> Arc<ServerConfig> {
+    build_server_config(LeafCertResolver {
+        ca,
+        fallback_host,
+        allow: None,
+    })
+}
+
+// Config for a listener that issues certificates on demand for hosts accepted by `allow`
+pub fn sni_server_config(
+    ca: Arc<CertificateAuthority>,
+    allow: impl Fn(&str) -> bool + Send + Sync + 'static,
+) -> Arc<ServerConfig> {
+    build_server_config(LeafCertResolver {
+        ca,
+        fallback_host: None,
+        allow: Some(Box::new(allow)),
+    })
+}
+
+// Accepts any upstream certificate, for self-signed dev backends. Handshake
+// signatures are still checked so the connection itself is sound.
+#[derive(Debug)]
+struct SkipServerVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for SkipServerVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+// Client config for upstream connections; `h2` also offers HTTP/2 via ALPN
+pub fn client_config(verify: bool, h2: bool) -> Arc<ClientConfig> {
+    static CONFIGS: [OnceLock<Arc<ClientConfig>>; 4] = [const { OnceLock::new() }; 4];
+
+    CONFIGS[usize::from(verify) * 2 + usize::from(h2)]
+        .get_or_init(|| {
+            let builder = ClientConfig::builder_with_provider(provider())
+                .with_safe_default_protocol_versions()
+                .expect("ring provider supports the default protocol versions");
+            let mut config = if verify {
+                let roots = RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+                builder.with_root_certificates(roots).with_no_client_auth()
+            } else {
+                builder
+                    .dangerous()
+                    .with_custom_certificate_verifier(Arc::new(SkipServerVerification(provider())))
+                    .with_no_client_auth()
+            };
+            config.alpn_protocols = if h2 {
+                vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+            } else {
+                vec![b"http/1.1".to_vec()]
+            };
+            Arc::new(config)
+        })
+        .clone()
+}
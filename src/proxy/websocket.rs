@@ -0,0 +1,388 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use hyper::header::{self, HeaderMap};
+use regex::Regex;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::sync::mpsc;
+
+// Frames larger than this end the connection rather than being buffered
+const MAX_FRAME_SIZE: u64 = 64 * 1024 * 1024;
+
+// Text previews are cut off after this many characters
+const PREVIEW_CHARS: usize = 2000;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+// Whether a request asks to be upgraded to a WebSocket
+pub fn is_upgrade(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::UPGRADE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.eq_ignore_ascii_case("websocket"))
+}
+
+// A rule's drop pattern; empty means nothing is dropped
+pub fn drop_pattern(pattern: &str) -> Result<Option<Regex>, String> {
+    let pattern = pattern.trim();
+    if pattern.is_empty() {
+        return Ok(None);
+    }
+    Regex::new(pattern)
+        .map(Some)
+        .map_err(|e| format!("WebSocket 丢弃规则的正则表达式无效: {}", e))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    ToServer,
+    ToClient,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Delivery {
+    Forwarded,
+    // Matched the rule's drop pattern and was not passed on
+    Dropped,
+    // Sent from the UI rather than by either peer
+    Injected,
+}
+
+// One frame seen on a proxied WebSocket
+#[derive(Clone, Debug)]
+pub struct WebSocketMessage {
+    pub connection: u64,
+    pub time: String,
+    pub direction: Direction,
+    pub opcode: u8,
+    pub size: u64,
+    pub delivery: Delivery,
+    // Text as is (JSON pretty-printed), a hex dump for binary data, or the close code and reason
+    pub preview: String,
+}
+
+impl WebSocketMessage {
+    pub fn kind(&self) -> &'static str {
+        match self.opcode {
+            OPCODE_CONTINUATION => "continuation",
+            OPCODE_TEXT if self.preview.starts_with(['{', '[']) => "json",
+            OPCODE_TEXT => "text",
+            OPCODE_BINARY => "binary",
+            OPCODE_CLOSE => "close",
+            OPCODE_PING => "ping",
+            OPCODE_PONG => "pong",
+            _ => "unknown",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum WebSocketEvent {
+    Message(WebSocketMessage),
+    Closed(u64),
+}
+
+struct Frame {
+    fin: bool,
+    // RSV bits and opcode, as in the first header byte
+    flags: u8,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    fn text(text: &str) -> Self {
+        Self {
+            fin: true,
+            flags: OPCODE_TEXT,
+            payload: text.as_bytes().to_vec(),
+        }
+    }
+
+    fn opcode(&self) -> u8 {
+        self.flags & 0x0f
+    }
+
+    // Frames towards the server must be masked, frames towards the client must not
+    fn encode(&self, direction: Direction) -> Vec<u8> {
+        let len = self.payload.len();
+        let mut frame = Vec::with_capacity(len + 14);
+        frame.push(if self.fin { 0x80 } else { 0 } | self.flags);
+
+        let mask_bit = if direction == Direction::ToServer { 0x80 } else { 0 };
+        if len < 126 {
+            frame.push(mask_bit | len as u8);
+        } else if len <= u16::MAX as usize {
+            frame.push(mask_bit | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        } else {
+            frame.push(mask_bit | 127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+
+        if direction == Direction::ToServer {
+            let mask: [u8; 4] = rand::random();
+            frame.extend_from_slice(&mask);
+            frame.extend(self.payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+        } else {
+            frame.extend_from_slice(&self.payload);
+        }
+        frame
+    }
+}
+
+// Read one frame and unmask its payload; None once the peer has closed
+async fn read_frame<R>(reader: &mut R) -> io::Result<Option<Frame>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut head = [0u8; 2];
+    match reader.read_exact(&mut head).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let len = match head[1] & 0x7f {
+        126 => reader.read_u16().await? as u64,
+        127 => reader.read_u64().await?,
+        len => len as u64,
+    };
+    if len > MAX_FRAME_SIZE {
+        return Err(io::Error::other(format!("WebSocket frame of {} bytes is too large", len)));
+    }
+    let mask = if head[1] & 0x80 != 0 {
+        let mut mask = [0u8; 4];
+        reader.read_exact(&mut mask).await?;
+        Some(mask)
+    } else {
+        None
+    };
+
+    let mut payload = vec![0u8; len as usize];
+    reader.read_exact(&mut payload).await?;
+    if let Some(mask) = mask {
+        payload.iter_mut().enumerate().for_each(|(i, byte)| *byte ^= mask[i % 4]);
+    }
+
+    Ok(Some(Frame {
+        fin: head[0] & 0x80 != 0,
+        flags: head[0] & 0x7f,
+        payload,
+    }))
+}
+
+fn preview(frame: &Frame) -> String {
+    let payload = &frame.payload;
+    match frame.opcode() {
+        OPCODE_CLOSE if payload.len() >= 2 => format!(
+            "{} {}",
+            u16::from_be_bytes([payload[0], payload[1]]),
+            String::from_utf8_lossy(&payload[2..])
+        ),
+        // Compressed payloads (RSV1) are not readable
+        OPCODE_TEXT | OPCODE_CONTINUATION if frame.flags & 0x40 == 0 => {
+            let text = String::from_utf8_lossy(payload);
+            let text = match serde_json::from_str::<serde_json::Value>(&text) {
+                Ok(json) if json.is_object() || json.is_array() => serde_json::to_string_pretty(&json).unwrap_or_default(),
+                _ => text.into_owned(),
+            };
+            match text.char_indices().nth(PREVIEW_CHARS) {
+                Some((end, _)) => format!("{}…", &text[..end]),
+                None => text,
+            }
+        }
+        _ => {
+            let hex = payload.iter().take(64).map(|byte| format!("{:02x}", byte)).collect::<Vec<_>>().join(" ");
+            if payload.len() > 64 { format!("{} …", hex) } else { hex }
+        }
+    }
+}
+
+// Live connections, so the UI can inject messages into them
+pub struct WebSocketHub {
+    next_id: AtomicU64,
+    live: Mutex<HashMap<u64, mpsc::Sender<(Direction, String)>>>,
+    events: mpsc::Sender<WebSocketEvent>,
+}
+
+impl WebSocketHub {
+    pub fn new(events: mpsc::Sender<WebSocketEvent>) -> Self {
+        Self {
+            next_id: AtomicU64::new(1),
+            live: Mutex::new(HashMap::new()),
+            events,
+        }
+    }
+
+    // Id for a connection about to be upgraded
+    pub fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    // Send a text message on a live connection; false once it has closed
+    pub fn inject(&self, connection: u64, direction: Direction, text: String) -> bool {
+        let sender = self.live.lock().ok().and_then(|live| live.get(&connection).cloned());
+        sender.is_some_and(|sender| sender.try_send((direction, text)).is_ok())
+    }
+}
+
+// Pass frames between the client and the server until either side closes,
+// reporting each one. Text messages matching `drop` are swallowed.
+pub async fn relay<C, S>(client: C, server: S, connection: u64, drop: Option<Regex>, hub: Arc<WebSocketHub>)
+where
+    C: AsyncRead + AsyncWrite + Send + 'static,
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (client_read, client_write) = tokio::io::split(client);
+    let (server_read, server_write) = tokio::io::split(server);
+    let to_client = Arc::new(tokio::sync::Mutex::new(Outgoing::new(client_write, Direction::ToClient, connection, hub.events.clone())));
+    let to_server = Arc::new(tokio::sync::Mutex::new(Outgoing::new(server_write, Direction::ToServer, connection, hub.events.clone())));
+
+    let (inject_tx, mut inject_rx) = mpsc::channel(16);
+    if let Ok(mut live) = hub.live.lock() {
+        live.insert(connection, inject_tx);
+    }
+
+    let upstream = tokio::spawn(pump(client_read, to_server.clone(), drop.clone()));
+    let downstream = tokio::spawn(pump(server_read, to_client.clone(), drop));
+    let injector = tokio::spawn(async move {
+        while let Some((direction, text)) = inject_rx.recv().await {
+            let frame = Frame::text(&text);
+            let written = match direction {
+                Direction::ToServer => to_server.lock().await.inject(frame).await,
+                Direction::ToClient => to_client.lock().await.inject(frame).await,
+            };
+            if let Err(e) = written {
+                println!("WebSocket {} write error: {}", connection, e);
+            }
+        }
+    });
+
+    // Either side closing ends the connection
+    let (upstream_abort, downstream_abort) = (upstream.abort_handle(), downstream.abort_handle());
+    tokio::select! {
+        _ = upstream => downstream_abort.abort(),
+        _ = downstream => upstream_abort.abort(),
+    }
+    injector.abort();
+
+    if let Ok(mut live) = hub.live.lock() {
+        live.remove(&connection);
+    }
+    let _ = hub.events.send(WebSocketEvent::Closed(connection)).await;
+}
+
+// The sending side of one direction. Frames of a fragmented message must not
+// be interleaved with another message, so injected messages wait until it ends.
+struct Outgoing<W> {
+    writer: WriteHalf<W>,
+    direction: Direction,
+    connection: u64,
+    events: mpsc::Sender<WebSocketEvent>,
+    // Between a non-final data frame and the final continuation frame
+    fragmented: bool,
+    pending: Vec<Frame>,
+}
+
+impl<W: AsyncWrite> Outgoing<W> {
+    fn new(writer: WriteHalf<W>, direction: Direction, connection: u64, events: mpsc::Sender<WebSocketEvent>) -> Self {
+        Self {
+            writer,
+            direction,
+            connection,
+            events,
+            fragmented: false,
+            pending: Vec::new(),
+        }
+    }
+
+    // Pass on a frame from the peer, then any injected messages it held back
+    async fn forward(&mut self, frame: &Frame) -> io::Result<()> {
+        self.writer.write_all(&frame.encode(self.direction)).await?;
+        self.report(frame, Delivery::Forwarded);
+        // Control frames may appear inside a fragmented message and don't end it
+        match frame.opcode() {
+            OPCODE_TEXT | OPCODE_BINARY | OPCODE_CONTINUATION => self.fragmented = !frame.fin,
+            _ => {}
+        }
+        if !self.fragmented {
+            for frame in std::mem::take(&mut self.pending) {
+                self.send_injected(frame).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn inject(&mut self, frame: Frame) -> io::Result<()> {
+        if self.fragmented {
+            self.pending.push(frame);
+            return Ok(());
+        }
+        self.send_injected(frame).await
+    }
+
+    async fn send_injected(&mut self, frame: Frame) -> io::Result<()> {
+        self.writer.write_all(&frame.encode(self.direction)).await?;
+        self.report(&frame, Delivery::Injected);
+        Ok(())
+    }
+
+    // Without waiting, so a busy UI can't hold up the connection
+    fn report(&self, frame: &Frame, delivery: Delivery) {
+        let message = WebSocketMessage {
+            connection: self.connection,
+            time: super::server::log_time(),
+            direction: self.direction,
+            opcode: frame.opcode(),
+            size: frame.payload.len() as u64,
+            delivery,
+            preview: preview(frame),
+        };
+        let _ = self.events.try_send(WebSocketEvent::Message(message));
+    }
+}
+
+async fn pump<R, W>(mut reader: ReadHalf<R>, outgoing: Arc<tokio::sync::Mutex<Outgoing<W>>>, drop: Option<Regex>)
+where
+    R: AsyncRead,
+    W: AsyncWrite,
+{
+    loop {
+        let frame = match read_frame(&mut reader).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(e) => {
+                let connection = outgoing.lock().await.connection;
+                println!("WebSocket {} read error: {}", connection, e);
+                break;
+            }
+        };
+
+        let mut outgoing = outgoing.lock().await;
+        let dropped = frame.opcode() == OPCODE_TEXT
+            && frame.fin
+            && drop.as_ref().is_some_and(|drop| drop.is_match(&String::from_utf8_lossy(&frame.payload)));
+        if dropped {
+            outgoing.report(&frame, Delivery::Dropped);
+            continue;
+        }
+
+        if let Err(e) = outgoing.forward(&frame).await {
+            println!("WebSocket {} write error: {}", outgoing.connection, e);
+            break;
+        }
+        if frame.opcode() == OPCODE_CLOSE && outgoing.direction == Direction::ToClient {
+            // The server has finished the closing handshake
+            break;
+        }
+    }
+
+    let _ = outgoing.lock().await.writer.shutdown().await;
+}